@@ -2,13 +2,10 @@ extern crate green;
 extern crate rustuv;
 extern crate tftp;
 
-use std::io;
-use std::io::fs::{File};
-use std::io::BufferedWriter;
 use std::io::net::ip::{SocketAddr, Ipv4Addr};
-use std::default::Default;
 
 use tftp::client;
+use tftp::{TransferOptionsBuilder, Octet};
 
 #[start]
 fn start(argc: int, argv: **u8) -> int {
@@ -17,12 +14,15 @@ fn start(argc: int, argv: **u8) -> int {
 
 fn main() {
     let args = std::os::args();
-    let path = Path::new("/tmp/tftp_test");
-    let opts: tftp::TransferOptions = Default::default();
-    let mut file = BufferedWriter::new(File::open_mode(&path, io::Truncate, io::Write));
-    let result = client::get(SocketAddr {
+    let opts = TransferOptionsBuilder::new()
+        .block_size(1428)
+        .timeout(1000)
+        .mode(Octet)
+        .build()
+        .unwrap();
+    let result = client::get_file(SocketAddr {
         ip: Ipv4Addr(127, 0, 0, 1),
         port: 69
-    }, Path::new(args.get(1).as_slice()), opts, &mut file);
+    }, Path::new(args.get(1).as_slice()), &Path::new("/tmp/tftp_test"), opts);
     println!("Result: {}", result);
 }
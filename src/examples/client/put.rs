@@ -1,7 +1,5 @@
 extern crate tftp;
 
-use std::io::fs::{File};
-use std::io::BufferedReader;
 use std::io::net::ip::{SocketAddr, Ipv4Addr};
 use std::default::Default;
 
@@ -10,11 +8,9 @@ use tftp::client;
 fn main() {
     let args = std::os::args();
     let opts: tftp::TransferOptions = Default::default();
-    let path = Path::new(args.get(2).clone());
-    let mut file = BufferedReader::new(File::open(&path));
-    let result = client::put(SocketAddr {
+    let result = client::put_file(SocketAddr {
         ip: Ipv4Addr(127, 0, 0, 1),
         port: 69
-    }, Path::new(args.get(1).as_slice()), opts, &mut file);
+    }, &Path::new(args.get(2).clone()), Path::new(args.get(1).as_slice()), opts);
     println!("Result: {}", result);
 }
@@ -1,71 +1,803 @@
 use std::io;
-use std::io::IoResult;
-use std::io::net::ip::{SocketAddr, Ipv4Addr};
+use std::u16;
+use std::cmp::min;
+use std::io::{IoResult, IoError, MemWriter, File};
+use std::io::{BufferedReader, BufferedWriter};
+use std::io::net::ip::SocketAddr;
+use std::io::net::udp::UdpSocket;
+use std::comm::{sync_channel, SyncSender, Full, RecvDisconnected};
+use std::collections::HashMap;
+use std::hash::sip::SipState;
 
 use protocol::{ReadRequest, WriteRequest, Data, Acknowledgment};
-use protocol::{OptionAcknowledgment, Packet, One};
-use util::{socket_reader, socket_writer, bind_socket};
+use protocol::{OptionAcknowledgment, Packet, BlockId, Options, Mode};
+use protocol::{IllegalOperation, OptionNegotiationRejected, Undefined};
+use protocol::{NetAscii, NetasciiDecoder, NetasciiReader};
+use util::{socket_reader, strict_socket_reader, socket_writer, bind_socket, receive_size};
+use util::{append_crc, strip_crc, CRC_SIZE};
+
+use time;
+
+use gzip;
+use common::{TransferOptions, TransferStats, RetryStrategy, NegotiatedRetry, RttEstimator, Control};
+use common::{TftpError, Io, Channels};
+use common::{GET_REPLIES, PUT_REPLIES};
+use common::{receive_loop, dally, next_block_id, prev_block_id, is_future_block, local_error_code};
+use common::{LoopData, LoopControl, Normal, Break, Return};
+
+/// Downloads `path` into `w`. An Error packet from the server is returned as
+/// `Protocol` with its error code.
+pub fn get(remote_addr: SocketAddr, path: Path, opts: TransferOptions, w: &mut Writer) -> Result<(), TftpError> {
+    get_with_stats(remote_addr, path, opts, w).val0()
+}
+
+/// Downloads `remote_path` into the file at `local_path`, which is created or
+/// truncated before the transfer starts.
+pub fn get_file(remote_addr: SocketAddr,
+                remote_path: Path,
+                local_path: &Path,
+                opts: TransferOptions) -> Result<(), TftpError> {
+    let file = try!(File::open_mode(local_path, io::Truncate, io::Write).map_err(Io));
+    let mut w = BufferedWriter::new(file);
+    try!(get(remote_addr, remote_path, opts, &mut w));
+    w.flush().map_err(Io)
+}
+
+/// Like `get`, but returns the number of bytes written to `w`, also when the
+/// transfer fails part way through.
+pub fn get_counted(remote_addr: SocketAddr,
+                   path: Path,
+                   opts: TransferOptions,
+                   w: &mut Writer) -> Result<u64, (u64, TftpError)> {
+    match get_with_stats(remote_addr, path, opts, w) {
+        (Ok(()), stats) => Ok(stats.transferred),
+        (Err(err), stats) => Err((stats.transferred, err))
+    }
+}
+
+pub fn get_with_stats(remote_addr: SocketAddr,
+                      path: Path,
+                      opts: TransferOptions,
+                      w: &mut Writer) -> (Result<(), TftpError>, TransferStats) {
+    match opts.validate() {
+        Ok(()) => {}
+        Err(err) => return (Err(Io(err)), TransferStats::new())
+    }
+    match open_channels(&opts) {
+        Ok((reader_recv, unknown_recv, writer_snd)) => {
+            let (res, stats) = get_internal(reader_recv, unknown_recv, None, writer_snd, remote_addr, path, opts, w);
+            let res = res.map_err(|err| TftpError::from_transfer(err, &stats));
+            (res, stats)
+        }
+        Err(err) => (Err(Io(err)), TransferStats::new())
+    }
+}
+
+// `opts.mode` is the only source of the mode: it is sent in the request and
+// decides whether the transfer translates netascii, so that line breaks may span
+// block boundaries. The socket tasks take no mode and never translate payloads.
+fn open_channels(opts: &TransferOptions) -> IoResult<Channels> {
+    let send_socket = try!(bind_socket(SocketAddr { ip: opts.local_addr, port: 0 }));
+    let receive_socket = if opts.split_sockets {
+        try!(bind_socket(SocketAddr { ip: opts.local_addr, port: 0 }))
+    } else {
+        send_socket.clone()
+    };
+    Ok(open_socket_channels(send_socket, receive_socket, opts))
+}
+
+// Our TID is the port of `send_socket`, the receive loop only checks the
+// server's TID, so replies are accepted on whichever socket they arrive
+fn open_socket_channels(send_socket: UdpSocket, receive_socket: UdpSocket, opts: &TransferOptions) -> Channels {
+    let size = receive_size(opts.block_size);
+    let (reader_recv, unknown_recv) = if opts.strict {
+        let (reader_recv, unknown_recv) = strict_socket_reader(receive_socket, size);
+        (reader_recv, Some(unknown_recv))
+    } else {
+        (socket_reader(receive_socket, size), None)
+    };
+    let writer_snd = socket_writer(send_socket);
+    (reader_recv, unknown_recv, writer_snd)
+}
+
+/// Callbacks and the control channel of a single transfer, all of them are
+/// optional.
+pub struct TransferHooks<'a> {
+    /// Called with the bytes transferred so far and the size acknowledged
+    /// through `tsize`, if any, after each received or acknowledged block.
+    pub progress: Option<|u64, Option<u64>|: 'a>,
+    /// Called with the id and size of each new block of an upload before its
+    /// data is read, so it may change the block size between blocks. The peer
+    /// however treats any short block as the last one.
+    pub block_hook: Option<|BlockId, &mut uint|: 'a>,
+    /// Pauses and resumes the transfer.
+    pub control: Option<Receiver<Control>>
+}
+
+impl<'a> TransferHooks<'a> {
+    pub fn new() -> TransferHooks<'a> {
+        TransferHooks {
+            progress: None,
+            block_hook: None,
+            control: None
+        }
+    }
+
+    pub fn progress(self, progress: |u64, Option<u64>|: 'a) -> TransferHooks<'a> {
+        TransferHooks { progress: Some(progress), ..self }
+    }
+
+    pub fn block_hook(self, block_hook: |BlockId, &mut uint|: 'a) -> TransferHooks<'a> {
+        TransferHooks { block_hook: Some(block_hook), ..self }
+    }
+
+    pub fn control(self, control: Receiver<Control>) -> TransferHooks<'a> {
+        TransferHooks { control: Some(control), ..self }
+    }
+}
+
+/// Like `get`, but reports the progress and is paused and resumed through
+/// `hooks`.
+pub fn get_with_hooks(remote_addr: SocketAddr,
+                      path: Path,
+                      opts: TransferOptions,
+                      hooks: TransferHooks,
+                      w: &mut Writer) -> Result<(), TftpError> {
+    try!(opts.validate().map_err(Io));
+    let (reader_recv, unknown_recv, writer_snd) = try!(open_channels(&opts).map_err(Io));
+    let (res, stats) = get_internal_attempts(reader_recv, unknown_recv, writer_snd, remote_addr, path, opts,
+                                             1, hooks, w);
+    res.map_err(|err| TftpError::from_transfer(err, &stats))
+}
+
+/// Like `get`, but a transfer that fails before any data is received is started
+/// over, up to `attempts` times in total, using the same socket.
+pub fn get_with_attempts(remote_addr: SocketAddr,
+                         path: Path,
+                         opts: TransferOptions,
+                         attempts: uint,
+                         w: &mut Writer) -> Result<(), TftpError> {
+    try!(opts.validate().map_err(Io));
+    let (reader_recv, unknown_recv, writer_snd) = try!(open_channels(&opts).map_err(Io));
+    let (res, stats) = get_internal_attempts(reader_recv, unknown_recv, writer_snd, remote_addr, path, opts,
+                                             attempts, TransferHooks::new(), w);
+    res.map_err(|err| TftpError::from_transfer(err, &stats))
+}
+
+/// Like `get`, but exactly `opts` is sent in the request. The acknowledged
+/// options are applied as usual.
+pub fn get_with_raw_options(remote_addr: SocketAddr,
+                            path: Path,
+                            mode: Mode,
+                            opts: Options,
+                            w: &mut Writer) -> Result<(), TftpError> {
+    // The requested block size decides how large the receive buffer is
+    let mut transfer_opts = TransferOptions::from_map(&opts);
+    transfer_opts.mode = mode;
+    transfer_opts.request_options = Some(opts);
+    let (reader_recv, unknown_recv, writer_snd) = try!(open_channels(&transfer_opts).map_err(Io));
+    let (res, stats) = get_internal(reader_recv, unknown_recv, None, writer_snd, remote_addr, path, transfer_opts, w);
+    res.map_err(|err| TftpError::from_transfer(err, &stats))
+}
+
+/// Like `get`, but the whole file is received into memory and only written to
+/// `w` once the transfer completed and matches the acknowledged `tsize`, so
+/// nothing is written when it fails.
+pub fn get_buffered(remote_addr: SocketAddr,
+                    path: Path,
+                    opts: TransferOptions,
+                    w: &mut Writer) -> Result<(), TftpError> {
+    try!(opts.validate().map_err(Io));
+    let (reader_recv, unknown_recv, writer_snd) = try!(open_channels(&opts).map_err(Io));
+    get_buffered_internal(reader_recv, unknown_recv, writer_snd, remote_addr, path, opts, w)
+}
+
+fn get_buffered_internal(reader_recv: Receiver<(SocketAddr, Packet)>,
+                         unknown_recv: Option<Receiver<(SocketAddr, u16)>>,
+                         writer_snd: Sender<(SocketAddr, Packet)>,
+                         remote_addr: SocketAddr,
+                         path: Path,
+                         opts: TransferOptions,
+                         w: &mut Writer) -> Result<(), TftpError> {
+    let mut buffer = MemWriter::new();
+    let (res, stats) = get_internal(reader_recv, unknown_recv, None, writer_snd, remote_addr, path, opts, &mut buffer);
+    // The size was already checked against the acknowledged tsize when the
+    // last block was received
+    try!(res.map_err(|err| TftpError::from_transfer(err, &stats)));
+    w.write(buffer.get_ref()).map_err(Io)
+}
+
+/// Like `get`, but `w` is written from a separate task through a queue of
+/// `queue_size` blocks. While the queue is full a block is not acknowledged and
+/// is written once the peer retransmits it, so a writer that stops accepting
+/// data fails the transfer when the peer gives up instead of blocking it forever.
+pub fn get_with_writer_task(remote_addr: SocketAddr,
+                            path: Path,
+                            opts: TransferOptions,
+                            queue_size: uint,
+                            w: Box<Writer + Send>) -> Result<(), TftpError> {
+    let mut task_writer = TaskWriter::spawn(w, queue_size);
+    let mut opts = opts;
+    opts.retry_would_block = true;
+    try!(get_with_stats(remote_addr, path, opts, &mut task_writer).val0());
+    task_writer.finish().map_err(Io)
+}
+
+struct TaskWriter {
+    blocks: SyncSender<Vec<u8>>,
+    errors: Receiver<IoError>,
+    done: Receiver<IoResult<()>>
+}
+
+impl TaskWriter {
+    fn spawn(w: Box<Writer + Send>, queue_size: uint) -> TaskWriter {
+        let (blocks_snd, blocks_rcv) = sync_channel::<Vec<u8>>(queue_size);
+        let (errors_snd, errors_rcv) = channel();
+        let (done_snd, done_rcv) = channel();
+        spawn(proc() {
+            let mut w = w;
+            for block in blocks_rcv.iter() {
+                match w.write(block.as_slice()) {
+                    Ok(()) => {}
+                    Err(err) => {
+                        let _ = errors_snd.send_opt(err.clone());
+                        let _ = done_snd.send_opt(Err(err));
+                        return
+                    }
+                }
+            }
+            let _ = done_snd.send_opt(w.flush());
+        });
+        TaskWriter {
+            blocks: blocks_snd,
+            errors: errors_rcv,
+            done: done_rcv
+        }
+    }
+
+    fn finish(self) -> IoResult<()> {
+        let TaskWriter { blocks, done, .. } = self;
+        drop(blocks);
+        done.recv()
+    }
+}
+
+impl Writer for TaskWriter {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        match self.errors.try_recv() {
+            Ok(err) => return Err(err),
+            Err(_) => {}
+        }
+        match self.blocks.try_send(Vec::from_slice(buf)) {
+            Ok(()) => Ok(()),
+            Err(Full(_)) => Err(IoError {
+                kind: io::ResourceUnavailable,
+                desc: "Writer is not keeping up",
+                detail: None
+            }),
+            Err(RecvDisconnected(_)) => Err(IoError {
+                kind: io::BrokenPipe,
+                desc: "Writer task has stopped",
+                detail: None
+            })
+        }
+    }
+}
+
+/// Like `get`, but every received block is written to all of `writers`. The
+/// transfer fails as soon as any of the writes fails.
+pub fn get_tee(remote_addr: SocketAddr,
+               path: Path,
+               opts: TransferOptions,
+               writers: &mut [&mut Writer]) -> Result<(), TftpError> {
+    let mut tee = TeeWriter { writers: writers };
+    get_with_stats(remote_addr, path, opts, &mut tee).val0()
+}
+
+struct TeeWriter<'a, 'b> {
+    writers: &'a mut [&'b mut Writer]
+}
+
+impl<'a, 'b> Writer for TeeWriter<'a, 'b> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        for w in self.writers.mut_iter() {
+            try!(w.write(buf));
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        for w in self.writers.mut_iter() {
+            try!(w.flush());
+        }
+        Ok(())
+    }
+}
+
+/// Asks the server for the size of `path` with a `tsize=0` read request and
+/// aborts the transfer as soon as the server replies. `None` means the size is
+/// unknown: the server ignored the option, or echoed `tsize=0` which can not be
+/// told apart from an empty file.
+pub fn query_size(remote_addr: SocketAddr, path: Path, opts: TransferOptions) -> Result<Option<u64>, TftpError> {
+    try!(opts.validate().map_err(Io));
+    let (reader_recv, unknown_recv, writer_snd) = try!(open_channels(&opts).map_err(Io));
+    query_size_internal(reader_recv, unknown_recv, writer_snd, remote_addr, path, opts)
+}
+
+fn query_size_internal(reader_recv: Receiver<(SocketAddr, Packet)>,
+                       unknown_recv: Option<Receiver<(SocketAddr, u16)>>,
+                       writer_snd: Sender<(SocketAddr, Packet)>,
+                       remote_addr: SocketAddr,
+                       path: Path,
+                       opts: TransferOptions) -> Result<Option<u64>, TftpError> {
+    let mut opts = opts;
+    opts.transfer_size = Some(0);
+    let topts = try!(probe_options_internal(reader_recv, unknown_recv, writer_snd, remote_addr, path, opts,
+                                            "Size query only"));
+    Ok(TransferOptions::from_map(&topts).transfer_size.and_then(|size| {
+        if size == 0 { None } else { Some(size) }
+    }))
+}
+
+/// Options the server acknowledges for reading `path` with the candidate
+/// options, the transfer is aborted before any data is sent. Empty when the
+/// server does not support options.
+pub fn probe_options(remote_addr: SocketAddr,
+                     path: Path,
+                     candidate: &TransferOptions) -> Result<Options, TftpError> {
+    try!(candidate.validate().map_err(Io));
+    let (reader_recv, unknown_recv, writer_snd) = try!(open_channels(candidate).map_err(Io));
+    probe_options_internal(reader_recv, unknown_recv, writer_snd, remote_addr, path, candidate.clone(),
+                           "Option probe only")
+}
+
+fn probe_options_internal(reader_recv: Receiver<(SocketAddr, Packet)>,
+                          unknown_recv: Option<Receiver<(SocketAddr, u16)>>,
+                          writer_snd: Sender<(SocketAddr, Packet)>,
+                          remote_addr: SocketAddr,
+                          path: Path,
+                          opts: TransferOptions,
+                          reason: &str) -> Result<Options, TftpError> {
+    let mut retry = NegotiatedRetry;
+    let recording = if opts.record { Some(Vec::new()) } else { None };
+    let mut loop_data = LoopData {
+        remote_addr: remote_addr,
+        reader_port: reader_recv,
+        unknown_port: unknown_recv,
+        writer_chan: writer_snd,
+        control_port: None,
+        paused: false,
+        last_sent: None,
+        opts: opts,
+        acknowledged_options: None,
+        current_id: 0,
+        resend: false,
+        transferred: 0,
+        blocks: 0,
+        unacked: 0,
+        recording: recording,
+        rtt: RttEstimator::new(),
+        resends: 0,
+        peer_error: None,
+        timeout: None,
+        path_handle: (),
+        data: HashMap::new()
+    };
+    let res = receive_loop(&mut loop_data, false, &mut retry, GET_REPLIES, |d| {
+        let path_str = path.as_str().unwrap().into_string();
+        let opts = d.opts.to_options();
+        d.send(ReadRequest(path_str, d.opts.mode, opts));
+    }, |_| Normal, |d, _, packet, _| {
+        match *packet {
+            OptionAcknowledgment(ref topts) => d.data = topts.clone(),
+            _ => {}
+        }
+        d.abort(OptionNegotiationRejected, reason.to_string());
+        Break
+    });
+    match res {
+        Ok(()) => Ok(loop_data.data),
+        Err(err) => Err(TftpError::from_transfer(err, &loop_data.stats()))
+    }
+}
+
+/// Consecutive repetitions of an already received block after which the peer
+/// is considered stuck.
+pub static MAX_REPEATED_BLOCKS: uint = 10;
+
+struct GetData {
+    // The next block, when it arrives before the current one
+    next_block: Option<(BlockId, Vec<u8>)>,
+    // Times the previous block was received again since the last new one
+    repeated: uint,
+    // Payload of a compressed transfer, decompressed once it is complete
+    compressed: Option<MemWriter>,
+    // Decoder of a netascii transfer, a CR may end one block and be completed
+    // by the next
+    netascii: Option<NetasciiDecoder>
+}
+
+impl GetData {
+    fn new(opts: &TransferOptions) -> GetData {
+        GetData {
+            next_block: None,
+            repeated: 0,
+            compressed: None,
+            netascii: if opts.mode == NetAscii { Some(NetasciiDecoder::new(opts.newline.clone())) } else { None }
+        }
+    }
+}
+
+/// Transfers files from and to one server through a single local socket. The
+/// socket and its reader and writer tasks are kept between transfers, but each
+/// transfer still starts at `remote_addr` and is answered from a new server TID.
+pub struct Client {
+    remote_addr: SocketAddr,
+    opts: TransferOptions,
+    // Only taken while a transfer is using them
+    channels: Option<Channels>
+}
+
+impl Client {
+    pub fn new(remote_addr: SocketAddr, opts: TransferOptions) -> Result<Client, TftpError> {
+        try!(opts.validate().map_err(Io));
+        let channels = try!(open_channels(&opts).map_err(Io));
+        Ok(Client {
+            remote_addr: remote_addr,
+            opts: opts,
+            channels: Some(channels)
+        })
+    }
 
-use common::TransferOptions;
-use common::{receive_loop, LoopData, Void, Normal, Break, Return};
+    /// Downloads `path` into `w`, like `get`.
+    pub fn get(&mut self, path: Path, w: &mut Writer) -> Result<(), TftpError> {
+        let channels = self.take_channels();
+        let (res, stats, channels) = get_on_channels(channels, self.remote_addr, path, self.opts.clone(),
+                                                     1, TransferHooks::new(), w);
+        self.channels = Some(channels);
+        res.map_err(|err| TftpError::from_transfer(err, &stats))
+    }
 
-pub fn get(remote_addr: SocketAddr, path: Path, opts: TransferOptions, w: &mut Writer) -> IoResult<()> {
-    let socket = try!(bind_socket(Ipv4Addr(127, 0, 0, 1)));
-    let reader_recv = socket_reader(socket.clone(), opts.mode, opts.block_size + 4);
-    let writer_snd = socket_writer(socket, opts.mode);
+    /// Uploads the data read from `r` as `path`, like `put`.
+    pub fn put(&mut self, path: Path, r: &mut Reader) -> Result<(), TftpError> {
+        let channels = self.take_channels();
+        let mut retry = NegotiatedRetry;
+        let (res, stats, channels) = put_on_channels(channels, self.remote_addr, path, self.opts.clone(),
+                                                     &mut retry, TransferHooks::new(), r);
+        self.channels = Some(channels);
+        res.map_err(|err| TftpError::from_transfer(err, &stats))
+    }
 
-    get_internal(reader_recv, writer_snd, remote_addr, path, opts, w)
+    // Packets still arriving from the previous transfer are dropped, they could
+    // otherwise be taken for the first reply of the next one
+    fn take_channels(&mut self) -> Channels {
+        let (reader_recv, unknown_recv, writer_snd) = self.channels.take().unwrap();
+        while reader_recv.try_recv().is_ok() {}
+        match unknown_recv {
+            Some(ref unknown_recv) => while unknown_recv.try_recv().is_ok() {},
+            None => {}
+        }
+        (reader_recv, unknown_recv, writer_snd)
+    }
 }
 
 fn get_internal(reader_recv: Receiver<(SocketAddr, Packet)>,
+                unknown_recv: Option<Receiver<(SocketAddr, u16)>>,
+                control_recv: Option<Receiver<Control>>,
                 writer_snd: Sender<(SocketAddr, Packet)>,
                 remote_addr: SocketAddr,
                 path: Path,
                 opts: TransferOptions,
-                w: &mut Writer) -> IoResult<()> {
+                w: &mut Writer) -> (IoResult<()>, TransferStats) {
+    let hooks = TransferHooks { control: control_recv, ..TransferHooks::new() };
+    get_internal_attempts(reader_recv, unknown_recv, writer_snd, remote_addr, path, opts, 1, hooks, w)
+}
+
+fn get_internal_attempts(reader_recv: Receiver<(SocketAddr, Packet)>,
+                         unknown_recv: Option<Receiver<(SocketAddr, u16)>>,
+                         writer_snd: Sender<(SocketAddr, Packet)>,
+                         remote_addr: SocketAddr,
+                         path: Path,
+                         opts: TransferOptions,
+                         attempts: uint,
+                         hooks: TransferHooks,
+                         w: &mut Writer) -> (IoResult<()>, TransferStats) {
+    let (res, stats, _) = get_on_channels((reader_recv, unknown_recv, writer_snd), remote_addr, path,
+                                          opts, attempts, hooks, w);
+    (res, stats)
+}
 
-    let loop_data = LoopData {
+/// Like `get_internal_attempts`, but the channels are returned once the
+/// transfer is finished.
+fn get_on_channels(channels: Channels,
+                   remote_addr: SocketAddr,
+                   path: Path,
+                   opts: TransferOptions,
+                   attempts: uint,
+                   hooks: TransferHooks,
+                   w: &mut Writer) -> (IoResult<()>, TransferStats, Channels) {
+
+    let (reader_recv, unknown_recv, writer_snd) = channels;
+    let TransferHooks { progress, control: control_recv, .. } = hooks;
+    let recording = if opts.record { Some(Vec::new()) } else { None };
+    let mut loop_data = LoopData {
         remote_addr: remote_addr,
         reader_port: reader_recv,
+        unknown_port: unknown_recv,
         writer_chan: writer_snd,
-        opts: opts,
+        control_port: control_recv,
+        paused: false,
+        last_sent: None,
+        opts: opts.clone(),
+        acknowledged_options: None,
         current_id: 1,
         resend: true,
+        transferred: 0,
+        blocks: 0,
+        unacked: 0,
+        recording: recording,
+        rtt: RttEstimator::new(),
+        resends: 0,
+        peer_error: None,
+        timeout: None,
         path_handle: w,
-        data: Void
+        data: GetData::new(&opts)
+    };
+    let started = time::precise_time_ns();
+    let mut progress = progress;
+    let mut report_progress = |transferred: u64, total: Option<u64>| {
+        match progress {
+            Some(ref mut progress) => (*progress)(transferred, total),
+            None => {}
+        }
     };
-    receive_loop(loop_data, false, |d| {
+    let mut attempt = 1;
+    loop {
+        match get_attempt(&mut loop_data, &path, &mut report_progress) {
+            // Nothing was written yet, so the transfer can start over
+            Err(ref err) if attempt < attempts && loop_data.blocks == 0 => {
+                info!("Retrying transfer after error: {}", err);
+            }
+            res => return loop_data.finish_with_channels(path.as_str().unwrap().into_string(), res, started)
+        }
+        attempt += 1;
+        loop_data.reset(remote_addr, opts.clone(), 1, true);
+        loop_data.data = GetData::new(&opts);
+    }
+}
+
+fn get_attempt(loop_data: &mut LoopData<&mut Writer, GetData>,
+               path: &Path,
+               progress: &mut |u64, Option<u64>|) -> IoResult<()> {
+    let mut retry = NegotiatedRetry;
+    let res = receive_loop(loop_data, false, &mut retry, GET_REPLIES, |d| {
         let path_str = path.as_str().unwrap().into_string();
-        d.writer_chan.send((remote_addr, ReadRequest(path_str, d.opts.mode, d.opts.to_options())));
+        let opts = d.opts.to_options();
+        d.send(ReadRequest(path_str, d.opts.mode, opts));
     }, |_| Normal, |d, first_packet, packet, reset| {
         match *packet {
             OptionAcknowledgment(ref topts) if first_packet => {
-                d.opts = TransferOptions::from_map(topts);
-                d.writer_chan.send((d.remote_addr, Acknowledgment(0)));
+                match d.accept_options(topts) {
+                    Ok(()) => {
+                        if d.opts.compress {
+                            d.data.compressed = Some(MemWriter::new());
+                        }
+                        d.send(Acknowledgment(0));
+                    }
+                    Err(err) => return Return(Err(err))
+                }
             }
-            Data(block_id, ref data) if block_id == d.current_id => {
-                if d.current_id == ::std::u16::MAX && d.opts.rollover == Some(One) {
-                    d.current_id = d.opts.rollover.map(|r| r as u16).unwrap_or(0);
+            // Our ack of the OACK was lost if it is retransmitted before any data
+            OptionAcknowledgment(ref topts) if d.blocks == 0 => {
+                if d.acknowledged_options.as_ref() == Some(topts) {
+                    d.send(Acknowledgment(0));
                 } else {
-                    d.current_id += 1;
+                    d.abort(IllegalOperation, "Conflicting option acknowledgment".to_string());
+                    return Return(Err(IoError {
+                        kind: io::InvalidInput,
+                        desc: "Received conflicting option acknowledgment",
+                        detail: None
+                    }))
                 }
+            }
+            Data(block_id, ref data) if block_id == d.current_id => {
                 *reset = true;
-                match d.path_handle.write(data.as_slice()) {
-                    Ok(_) => {}
-                    err@Err(_) => return Return(err)
+                match receive_block(d, block_id, data.as_slice(), &mut *progress) {
+                    Normal => {}
+                    control => return control
                 }
-                d.writer_chan.send((d.remote_addr, Acknowledgment(block_id)));
-                if data.len() < d.opts.block_size {
-                    return Break
+                // The next block may have arrived before this one
+                match d.data.next_block.take() {
+                    Some((next_id, ref next)) if next_id == d.current_id => {
+                        return receive_block(d, next_id, next.as_slice(), &mut *progress)
+                    }
+                    _ => {}
+                }
+            }
+            Data(block_id, ref data) if block_id == next_block_id(&d.opts, d.current_id) => {
+                d.data.next_block = Some((block_id, data.clone()));
+            }
+            // Block ids start at 1, block 0 is only valid after a rollover
+            Data(0, _) if d.blocks < u16::MAX as u64 => {
+                d.abort(IllegalOperation, "Invalid data block 0".to_string());
+                return Return(Err(IoError {
+                    kind: io::InvalidInput,
+                    desc: "Received invalid data block 0",
+                    detail: None
+                }))
+            }
+            Data(..) if d.unacked > 0 => {
+                // A gap in the window, acknowledge the last block received in order
+                let last_id = prev_block_id(&d.opts, d.current_id);
+                d.send(Acknowledgment(last_id));
+                d.unacked = 0;
+            }
+            // The peer did not get our ack, it is sent again until the peer
+            // has resent the block too many times to still be making progress
+            Data(block_id, _) if d.blocks > 0 && block_id == prev_block_id(&d.opts, d.current_id) => {
+                d.data.repeated += 1;
+                if d.data.repeated >= MAX_REPEATED_BLOCKS {
+                    d.abort(Undefined, "Acknowledgments are not getting through".to_string());
+                    return Return(Err(IoError {
+                        kind: io::ConnectionAborted,
+                        desc: "Peer is not advancing",
+                        detail: Some(format!("block {} received {} more times despite being acknowledged",
+                                             block_id, d.data.repeated))
+                    }))
                 }
+                d.send(Acknowledgment(block_id));
             }
             _ => {}
         }
         Normal
-    })
+    });
+    let res = match (res, loop_data.data.compressed.take()) {
+        (Ok(()), Some(compressed)) => {
+            let netascii = loop_data.data.netascii.take();
+            gzip::decompress(compressed.get_ref()).and_then(|data| {
+                match netascii {
+                    Some(mut decoder) => {
+                        let decoded = try!(decoder.decode(data.as_slice()));
+                        try!(decoder.finish());
+                        write_block(loop_data.path_handle, decoded.as_slice())
+                    }
+                    None => write_block(loop_data.path_handle, data.as_slice())
+                }
+            })
+        }
+        (res, _) => res
+    };
+    match res {
+        Ok(()) if loop_data.opts.dally_timeout > 0 => {
+            // Only the final block is short, a retransmission means our ack was lost
+            let (timeout, block_size) = (loop_data.opts.dally_timeout, loop_data.opts.block_size);
+            dally(loop_data, timeout, |packet| {
+                match *packet {
+                    Data(block_id, ref data) if data.len() < block_size => Some(Acknowledgment(block_id)),
+                    _ => None
+                }
+            })
+        }
+        res => res
+    }
+}
+
+fn receive_block(d: &mut LoopData<&mut Writer, GetData>,
+                 block_id: BlockId,
+                 block: &[u8],
+                 progress: &mut |u64, Option<u64>|) -> LoopControl<IoResult<()>> {
+    let last = block.len() < d.opts.block_size;
+    let data = if d.opts.crc {
+        match strip_crc(block) {
+            Some(data) => data,
+            None => {
+                debug!("Checksum mismatch in block {}, waiting for it to be retransmitted", block_id);
+                return Normal
+            }
+        }
+    } else {
+        block
+    };
+    match d.opts.max_blocks {
+        Some(max) if d.blocks >= max => {
+            d.abort(Undefined, "too many blocks".to_string());
+            return Return(Err(IoError {
+                kind: io::ConnectionAborted,
+                desc: "Too many blocks",
+                detail: Some(format!("block {} exceeds the limit of {} blocks", block_id, max))
+            }))
+        }
+        _ => {}
+    }
+    // Only kept once the block is written, a retransmission is decoded again.
+    // A compressed payload is decoded as a whole once it is decompressed.
+    let mut netascii = match d.data.compressed {
+        Some(_) => None,
+        None => d.data.netascii.clone()
+    };
+    let decoded = match netascii {
+        Some(ref mut decoder) => {
+            match decoder.decode(data) {
+                Ok(decoded) => Some(decoded),
+                Err(err) => {
+                    d.abort(local_error_code(&err), err.desc.to_string());
+                    return Return(Err(err))
+                }
+            }
+        }
+        None => None
+    };
+    let data = decoded.as_ref().map_or(data, |decoded| decoded.as_slice());
+    let written = match d.data.compressed {
+        Some(ref mut compressed) => compressed.write(data),
+        None => write_block(d.path_handle, data)
+    };
+    match written {
+        Ok(_) => {
+            d.transferred += data.len() as u64;
+            if netascii.is_some() {
+                d.data.netascii = netascii;
+            }
+        }
+        Err(ref err) if err.kind == io::ResourceUnavailable && d.opts.retry_would_block => {
+            debug!("Writer would block, waiting for block {} to be retransmitted", block_id);
+            return Normal
+        }
+        Err(err) => {
+            d.abort(local_error_code(&err), err.desc.to_string());
+            return Return(Err(err))
+        }
+    }
+    d.current_id = next_block_id(&d.opts, d.current_id);
+    d.blocks += 1;
+    d.log_progress();
+    d.data.repeated = 0;
+    (*progress)(d.transferred, d.opts.transfer_size);
+    d.unacked += 1;
+    if last || d.unacked >= d.opts.window_size as uint {
+        d.send(Acknowledgment(block_id));
+        d.unacked = 0;
+    }
+    if last {
+        match d.data.netascii.as_ref().map_or(Ok(()), |decoder| decoder.finish()) {
+            Ok(()) => {}
+            err => return Return(err)
+        }
+        // Netascii and compression change the length, `tsize` is the size of the
+        // file. An echoed 0 means the server does not know it.
+        match d.opts.transfer_size {
+            Some(size) if size > 0 && size != d.transferred
+                          && d.data.netascii.is_none() && d.data.compressed.is_none() => {
+                return Return(Err(IoError {
+                    kind: io::OtherIoError,
+                    desc: "Transfer size does not match the advertised tsize",
+                    detail: Some(format!("advertised {} bytes, received {}", size, d.transferred))
+                }))
+            }
+            _ => return Break
+        }
+    }
+    Normal
+}
+
+/// Writes the whole block to `w`. A writer that reports a `ShortWrite` is
+/// given the rest of the block until it is written or the writer fails.
+pub fn write_block(w: &mut Writer, block: &[u8]) -> IoResult<()> {
+    let mut rest = block;
+    loop {
+        match w.write(rest) {
+            Err(IoError { kind: io::ShortWrite(n), .. }) if n > 0 && n < rest.len() => {
+                rest = rest.slice_from(n);
+            }
+            res => return res
+        }
+    }
 }
 
 pub fn read_block(r: &mut Reader, block_size: uint) -> IoResult<Vec<u8>> {
+    if block_size == 0 {
+        return Ok(Vec::new())
+    }
     let mut buf = Vec::from_elem(block_size, 0u8);
     match r.read(buf.as_mut_slice()) {
         Ok(len) => {
@@ -85,71 +817,446 @@ pub fn read_block(r: &mut Reader, block_size: uint) -> IoResult<Vec<u8>> {
     }
 }
 
-pub fn put(remote_addr: SocketAddr, path: Path, opts: TransferOptions, r: &mut Reader) -> IoResult<()> {
-    let socket = try!(bind_socket(Ipv4Addr(127, 0, 0, 1)));
-    let reader_recv = socket_reader(socket.clone(), opts.mode, opts.block_size + 4);
-    let writer_snd = socket_writer(socket, opts.mode);
+/// Uploads the data read from `r` as `path`. An Error packet from the server
+/// is returned as `Protocol` with its error code.
+pub fn put(remote_addr: SocketAddr, path: Path, opts: TransferOptions, r: &mut Reader) -> Result<(), TftpError> {
+    put_with_stats(remote_addr, path, opts, r).val0()
+}
+
+/// Uploads the file at `local_path` as `remote_path`. With
+/// `auto_transfer_size` its size is sent as `tsize`.
+pub fn put_file(remote_addr: SocketAddr,
+                local_path: &Path,
+                remote_path: Path,
+                opts: TransferOptions) -> Result<(), TftpError> {
+    let mut file = try!(File::open(local_path).map_err(Io));
+    let mut opts = opts;
+    if opts.auto_transfer_size && opts.transfer_size.is_none() {
+        let stat = try!(file.stat().map_err(Io));
+        opts.transfer_size = Some(stat.size);
+    }
+    put(remote_addr, remote_path, opts, &mut BufferedReader::new(file))
+}
+
+/// Like `put`, but also returns the stats of the transfer, including the
+/// options that were negotiated with the peer.
+pub fn put_with_stats(remote_addr: SocketAddr,
+                      path: Path,
+                      opts: TransferOptions,
+                      r: &mut Reader) -> (Result<(), TftpError>, TransferStats) {
+    match opts.validate() {
+        Ok(()) => {}
+        Err(err) => return (Err(Io(err)), TransferStats::new())
+    }
+    let mut retry = NegotiatedRetry;
+    match open_channels(&opts) {
+        Ok((reader_recv, unknown_recv, writer_snd)) => {
+            let (res, stats) = put_internal(reader_recv, unknown_recv, writer_snd, remote_addr, path, opts,
+                                            &mut retry, TransferHooks::new(), r);
+            let res = res.map_err(|err| TftpError::from_transfer(err, &stats));
+            (res, stats)
+        }
+        Err(err) => (Err(Io(err)), TransferStats::new())
+    }
+}
+
+pub fn put_with_retry(remote_addr: SocketAddr,
+                      path: Path,
+                      opts: TransferOptions,
+                      retry: &mut RetryStrategy,
+                      r: &mut Reader) -> Result<(), TftpError> {
+    try!(opts.validate().map_err(Io));
+    let (reader_recv, unknown_recv, writer_snd) = try!(open_channels(&opts).map_err(Io));
+    let (res, stats) = put_internal(reader_recv, unknown_recv, writer_snd, remote_addr, path, opts, retry,
+                                    TransferHooks::new(), r);
+    res.map_err(|err| TftpError::from_transfer(err, &stats))
+}
+
+/// Like `put`, but reports the progress, may change the block size and is
+/// paused and resumed through `hooks`.
+pub fn put_with_hooks(remote_addr: SocketAddr,
+                      path: Path,
+                      opts: TransferOptions,
+                      hooks: TransferHooks,
+                      r: &mut Reader) -> Result<(), TftpError> {
+    try!(opts.validate().map_err(Io));
+    let mut retry = NegotiatedRetry;
+    let (reader_recv, unknown_recv, writer_snd) = try!(open_channels(&opts).map_err(Io));
+    let (res, stats) = put_internal(reader_recv, unknown_recv, writer_snd, remote_addr, path, opts, &mut retry,
+                                    hooks, r);
+    res.map_err(|err| TftpError::from_transfer(err, &stats))
+}
+
+/// Like `put`, but the data is pulled from `source` only as it is sent. The
+/// chunks may have any size, they are joined and split into blocks of the
+/// negotiated block size.
+pub fn put_from_iter<I: Iterator<Vec<u8>>>(remote_addr: SocketAddr,
+                                           path: Path,
+                                           opts: TransferOptions,
+                                           source: I) -> Result<(), TftpError> {
+    let mut reader = IterReader::new(source);
+    put_with_stats(remote_addr, path, opts, &mut reader).val0()
+}
+
+// Every read is filled from as many chunks as needed, so only a block read at
+// the end of the source is short.
+struct IterReader<I> {
+    source: I,
+    chunk: Vec<u8>,
+    // Bytes of `chunk` already read
+    pos: uint
+}
+
+impl<I: Iterator<Vec<u8>>> IterReader<I> {
+    fn new(source: I) -> IterReader<I> {
+        IterReader {
+            source: source,
+            chunk: Vec::new(),
+            pos: 0
+        }
+    }
+}
+
+impl<I: Iterator<Vec<u8>>> Reader for IterReader<I> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        let mut len = 0;
+        while len < buf.len() {
+            if self.pos == self.chunk.len() {
+                match self.source.next() {
+                    Some(chunk) => {
+                        self.chunk = chunk;
+                        self.pos = 0;
+                        continue
+                    }
+                    None => break
+                }
+            }
+            let n = min(buf.len() - len, self.chunk.len() - self.pos);
+            buf.mut_slice(len, len + n).copy_from(self.chunk.slice(self.pos, self.pos + n));
+            len += n;
+            self.pos += n;
+        }
+        if len == 0 && !buf.is_empty() {
+            return Err(io::standard_error(io::EndOfFile))
+        }
+        Ok(len)
+    }
+}
+
+/// Uploads the file at `local_path` as `remote_path`, then downloads it again
+/// from the same socket and fails when its hash differs from the hash of the
+/// uploaded data, so a server that stored something else is noticed.
+pub fn put_verified(remote_addr: SocketAddr,
+                    local_path: &Path,
+                    remote_path: Path,
+                    opts: TransferOptions) -> Result<(), TftpError> {
+    try!(opts.validate().map_err(Io));
+    let mut file = try!(File::open(local_path).map_err(Io));
+    let channels = try!(open_channels(&opts).map_err(Io));
+    put_verified_on_channels(channels, remote_addr, remote_path, opts, &mut file)
+}
+
+fn put_verified_on_channels(channels: Channels,
+                            remote_addr: SocketAddr,
+                            path: Path,
+                            opts: TransferOptions,
+                            r: &mut Reader) -> Result<(), TftpError> {
+    let mut uploaded = HashingReader { inner: r, state: SipState::new() };
+    let mut retry = NegotiatedRetry;
+    let (res, stats, channels) = put_on_channels(channels, remote_addr, path.clone(), opts.clone(),
+                                                 &mut retry, TransferHooks::new(), &mut uploaded);
+    try!(res.map_err(|err| TftpError::from_transfer(err, &stats)));
+    // The size of the upload is not requested again
+    let mut get_opts = opts;
+    get_opts.transfer_size = None;
+    let mut downloaded = HashWriter { state: SipState::new() };
+    let (res, stats, _) = get_on_channels(channels, remote_addr, path, get_opts, 1, TransferHooks::new(),
+                                          &mut downloaded);
+    try!(res.map_err(|err| TftpError::from_transfer(err, &stats)));
+    let (uploaded, downloaded) = (uploaded.state.result(), downloaded.state.result());
+    if uploaded != downloaded {
+        return Err(Io(IoError {
+            kind: io::OtherIoError,
+            desc: "Downloaded file does not match the upload",
+            detail: Some(format!("uploaded hash {:016x}, downloaded hash {:016x}", uploaded, downloaded))
+        }))
+    }
+    Ok(())
+}
+
+fn hash_bytes(state: &mut SipState, buf: &[u8]) {
+    // Shadows the prelude's io::Writer
+    use std::hash::Writer;
+    state.write(buf)
+}
+
+struct HashingReader<'a> {
+    inner: &'a mut Reader,
+    state: SipState
+}
+
+impl<'a> Reader for HashingReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        let len = try!(self.inner.read(buf));
+        hash_bytes(&mut self.state, buf.slice_to(len));
+        Ok(len)
+    }
+}
+
+struct HashWriter {
+    state: SipState
+}
 
-    put_internal(reader_recv, writer_snd, remote_addr, path, opts, r)
+impl Writer for HashWriter {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        hash_bytes(&mut self.state, buf);
+        Ok(())
+    }
 }
 
+// Only the blocks of the current window are kept in memory until acknowledged,
+// the reader is read at most one block further ahead with `read_ahead`, so
+// memory use does not depend on the size of the upload.
+// The block hook of `hooks` may change the block size between blocks. It gets
+// no access to the other options so the mode can not diverge from the one the
+// reader was wrapped for. Progress is reported whenever a block is acknowledged.
 fn put_internal(reader_recv: Receiver<(SocketAddr, Packet)>,
+                unknown_recv: Option<Receiver<(SocketAddr, u16)>>,
                 writer_snd: Sender<(SocketAddr, Packet)>,
                 remote_addr: SocketAddr,
                 path: Path,
                 opts: TransferOptions,
-                r: &mut Reader) -> IoResult<()> {
+                retry: &mut RetryStrategy,
+                hooks: TransferHooks,
+                r: &mut Reader) -> (IoResult<()>, TransferStats) {
+    let (res, stats, _) = put_on_channels((reader_recv, unknown_recv, writer_snd), remote_addr, path,
+                                          opts, retry, hooks, r);
+    (res, stats)
+}
 
-    let loop_data = LoopData {
+/// Like `put_internal`, but the channels are returned once the transfer is
+/// finished.
+fn put_on_channels(channels: Channels,
+                   remote_addr: SocketAddr,
+                   path: Path,
+                   opts: TransferOptions,
+                   retry: &mut RetryStrategy,
+                   hooks: TransferHooks,
+                   r: &mut Reader) -> (IoResult<()>, TransferStats, Channels) {
+
+    let (reader_recv, unknown_recv, writer_snd) = channels;
+    let TransferHooks { progress, block_hook, control: control_recv } = hooks;
+    let started = time::precise_time_ns();
+    let recording = if opts.record { Some(Vec::new()) } else { None };
+    // Encoded as one stream, so a line break may span two blocks
+    let mut netascii;
+    let r = if opts.mode == NetAscii {
+        netascii = NetasciiReader::new(r);
+        &mut netascii as &mut Reader
+    } else {
+        r
+    };
+    // Compresses the upload a member at a time once compression is acknowledged
+    let mut compressor: Option<gzip::Compressor> = None;
+    // Block following the window, read before it is needed with `read_ahead`
+    let mut ahead: Option<WindowBlock> = None;
+    let mut block_hook = block_hook;
+    let mut progress = progress;
+    let mut loop_data = LoopData {
         remote_addr: remote_addr,
         reader_port: reader_recv,
+        unknown_port: unknown_recv,
         writer_chan: writer_snd,
+        control_port: control_recv,
+        paused: false,
+        last_sent: None,
         opts: opts,
+        acknowledged_options: None,
         current_id: 0,
         resend: false,
+        transferred: 0,
+        blocks: 0,
+        unacked: 0,
+        recording: recording,
+        rtt: RttEstimator::new(),
+        resends: 0,
+        peer_error: None,
+        timeout: None,
         path_handle: r,
-        data: None
+        data: Vec::new()
     };
-    receive_loop(loop_data, true, |d| {
+    let res = receive_loop(&mut loop_data, true, retry, PUT_REPLIES, |d| {
         let path_str = path.as_str().unwrap().into_string();
-        d.writer_chan.send((d.remote_addr, WriteRequest(path_str, d.opts.mode, d.opts.to_options())));
+        let opts = d.opts.to_options();
+        d.send(WriteRequest(path_str, d.opts.mode, opts));
     }, |d| {
         if d.resend {
-            if d.data.is_none() {
-                match read_block(d.path_handle, d.opts.block_size) {
-                    Ok(data) => d.data = Some(data),
-                    Err(err) => return Return(Err(err))
+            // Blocks are kept until acknowledged, so a lost ack of the final
+            // block is recovered by resending it like any other block. The
+            // window is filled up and sent again from its first block, which
+            // also restarts it after an ack from the middle of the window.
+            let mut block_id = d.current_id;
+            for _ in d.data.iter() {
+                block_id = next_block_id(&d.opts, block_id);
+            }
+            while d.data.len() < d.opts.window_size as uint && !window_ends_transfer(d) {
+                let mut block = match ahead.take() {
+                    Some(block) => block,
+                    None => {
+                        match read_next_block(d, &mut compressor, &mut block_hook, block_id) {
+                            Ok(block) => block,
+                            Err(err) => return Return(Err(err))
+                        }
+                    }
+                };
+                d.transferred += block.size;
+                d.blocks += 1;
+                d.log_progress();
+                if d.opts.crc {
+                    append_crc(&mut block.data);
                 }
+                d.data.push(block);
+                block_id = next_block_id(&d.opts, block_id);
+            }
+            let mut block_id = d.current_id;
+            for i in range(0, d.data.len()) {
+                let data = d.data.get(i).data.clone();
+                d.send(Data(block_id, data));
+                block_id = next_block_id(&d.opts, block_id);
             }
-            let data = Vec::from_slice(d.data.as_ref().unwrap().as_slice());
-            d.writer_chan.send((d.remote_addr, Data(d.current_id, data)));
             d.resend = false;
+            // The next block is read while waiting for the acknowledgment
+            if d.opts.read_ahead && ahead.is_none() && !window_ends_transfer(d) {
+                match read_next_block(d, &mut compressor, &mut block_hook, block_id) {
+                    Ok(block) => ahead = Some(block),
+                    Err(err) => return Return(Err(err))
+                }
+            }
         }
         Normal
     }, |d, first_packet, packet, reset| {
         match *packet {
             OptionAcknowledgment(ref topts) if first_packet=> {
-                d.opts = TransferOptions::from_map(topts);
+                match d.accept_options(topts) {
+                    Ok(()) => {}
+                    Err(err) => return Return(Err(err))
+                }
                 d.current_id += 1;
                 d.resend = true;
             }
-            Acknowledgment(block_id) if block_id == d.current_id => {
-                if d.data.is_some() && d.data.as_ref().unwrap().len() < d.opts.block_size {
-                     return Break
+            // The request itself was acknowledged
+            Acknowledgment(block_id) if block_id == d.current_id && d.data.is_empty() => {
+                d.current_id = next_block_id(&d.opts, d.current_id);
+                *reset = true;
+                d.resend = true;
+            }
+            Acknowledgment(block_id) if window_position(&d.opts, d.current_id, d.data.len(), block_id).is_some() => {
+                let acked = window_position(&d.opts, d.current_id, d.data.len(), block_id).unwrap() + 1;
+                let last = d.data.get(acked - 1).is_last();
+                for _ in range(0, acked) {
+                    d.data.remove(0);
+                    d.current_id = next_block_id(&d.opts, d.current_id);
                 }
-                if d.current_id == ::std::u16::MAX && d.opts.rollover == Some(One) {
-                    d.current_id = d.opts.rollover.map(|r| r as u16).unwrap_or(0);
-                } else {
-                    d.current_id += 1;
+                let pending = d.data.iter().fold(0, |sum, block| sum + block.size);
+                match progress {
+                    Some(ref mut progress) => (*progress)(d.transferred - pending, d.opts.transfer_size),
+                    None => {}
+                }
+                if last {
+                    // Netascii changes the length, `tsize` is the size of the
+                    // data read from the reader before it is encoded.
+                    match d.opts.transfer_size {
+                        Some(size) if size != d.transferred && d.opts.mode != NetAscii => {
+                            return Return(Err(IoError {
+                                kind: io::OtherIoError,
+                                desc: "Transfer size does not match the advertised tsize",
+                                detail: Some(format!("advertised {} bytes, sent {}", size, d.transferred))
+                            }))
+                        }
+                        _ => {}
+                    }
+                    return Break
                 }
                 *reset = true;
                 d.resend = true;
-                d.data = None;
+            }
+            Acknowledgment(block_id) if is_future_block(d.current_id, block_id) => {
+                d.abort(IllegalOperation, "Acknowledgment of an unsent block".to_string());
+                return Return(Err(IoError {
+                    kind: io::InvalidInput,
+                    desc: "Received acknowledgment of an unsent block",
+                    detail: Some(format!("block {}, last sent {}", block_id, d.current_id))
+                }))
             }
             _ => ()
         }
         Normal
-    })
+    });
+    loop_data.finish_with_channels(path.as_str().unwrap().into_string(), res, started)
+}
+
+// A block of the window, with the bytes of the upload it carries. They differ
+// from the length of the block when the upload is compressed. The block size it
+// was read with is kept, the block hook may change it for the following blocks.
+struct WindowBlock {
+    data: Vec<u8>,
+    size: u64,
+    block_size: uint
+}
+
+impl WindowBlock {
+    // Whether this is the final, short block.
+    fn is_last(&self) -> bool {
+        self.data.len() < self.block_size
+    }
+}
+
+// Whether the window ends with the final, short block.
+fn window_ends_transfer(d: &LoopData<&mut Reader, Vec<WindowBlock>>) -> bool {
+    d.data.last().map_or(false, |last| last.is_last())
+}
+
+// Reads the payload of the block `block_id`, leaving room for its checksum.
+fn read_next_block(d: &mut LoopData<&mut Reader, Vec<WindowBlock>>,
+                   compressor: &mut Option<gzip::Compressor>,
+                   block_hook: &mut Option<|BlockId, &mut uint|>,
+                   block_id: BlockId) -> IoResult<WindowBlock> {
+    if d.opts.compress && compressor.is_none() {
+        *compressor = Some(gzip::Compressor::new(d.opts.max_upload_bytes));
+    }
+    match *block_hook {
+        Some(ref mut block_hook) => (*block_hook)(block_id, &mut d.opts.block_size),
+        None => {}
+    }
+    let full_size = d.opts.block_size;
+    let block_size = if d.opts.crc { full_size - CRC_SIZE } else { full_size };
+    match *compressor {
+        Some(ref mut compressor) => {
+            let consumed = compressor.consumed();
+            let data = try!(read_block(&mut compressor.reader(d.path_handle), block_size));
+            Ok(WindowBlock { data: data, size: compressor.consumed() - consumed, block_size: full_size })
+        }
+        None => {
+            let size = match d.opts.max_upload_bytes {
+                Some(max) => min(block_size as u64, max - d.transferred) as uint,
+                None => block_size
+            };
+            let data = try!(read_block(d.path_handle, size));
+            Ok(WindowBlock { size: data.len() as u64, data: data, block_size: full_size })
+        }
+    }
+}
+
+// Position of `block_id` among the `len` blocks of the window starting at `first`.
+fn window_position(opts: &TransferOptions, first: BlockId, len: uint, block_id: BlockId) -> Option<uint> {
+    let mut id = first;
+    for i in range(0, len) {
+        if id == block_id {
+            return Some(i)
+        }
+        id = next_block_id(opts, id);
+    }
+    None
 }
 
 #[cfg(test)]
@@ -157,23 +1264,53 @@ mod test {
     use std::io;
     use std::io::{IoResult, IoError};
     use std::io::net::ip::{SocketAddr, Ipv4Addr};
+    use std::io::timer;
     use std::default::Default;
 
     use std::collections::HashMap;
 
-    use super::{get_internal, put_internal};
-    use common::TransferOptions;
+    use super::{get, put, get_internal, get_internal_attempts, put_internal};
+    use super::{get_buffered_internal, query_size_internal, probe_options_internal};
+    use super::{TaskWriter, TeeWriter, IterReader, Client, TransferHooks, MAX_REPEATED_BLOCKS};
+    use super::{open_socket_channels, put_verified_on_channels};
+    use gzip;
+    use util::{bind_socket, strict_socket_reader, receive_size, receive_packet, send_packet};
+    use util::{append_crc, CRC_SIZE};
+    use common::{TransferOptions, RetryStrategy, FixedRetry, ExponentialBackoff, NegotiatedRetry};
+    use common::{Direction, Sent, Received};
+    use common::{NoResponse, TransferStalled, RetriesExhausted, DeadlineExceeded};
+    use common::{Pause, Resume};
+    use common::{TftpError, Protocol, Timeout, Io};
     use protocol::DEFAULT_BLOCK_SIZE;
-    use protocol::{Packet, Data, Acknowledgment, ReadRequest, Octet, WriteRequest, Zero, One, OptionAcknowledgment};
+    use protocol::{Packet, Data, Acknowledgment, ReadRequest, Octet, WriteRequest, ToZero, ToOne, OptionAcknowledgment};
+    use protocol::{NetAscii, NetasciiReader, NetasciiDecoder, Lf, CrLf};
+    use protocol::{Error, IllegalOperation, OptionNegotiationRejected, FileNotFound, Undefined, DiskFull};
+    use protocol::AccessViolation;
 
     static LOCALHOST: SocketAddr = SocketAddr {
         ip: Ipv4Addr(127, 0, 0, 1),
         port: 60000
     };
 
-    static ERR_TIMEOUT: IoError = IoError {
+    static EPHEMERAL: SocketAddr = SocketAddr {
+        ip: Ipv4Addr(127, 0, 0, 1),
+        port: 0
+    };
+
+    static OTHER_PORT: SocketAddr = SocketAddr {
+        ip: Ipv4Addr(127, 0, 0, 1),
+        port: 60001
+    };
+
+    static ERR_NO_RESPONSE: IoError = IoError {
+        kind: io::ConnectionAborted,
+        desc: "No response from peer",
+        detail: None
+    };
+
+    static ERR_STALLED: IoError = IoError {
         kind: io::ConnectionAborted,
-        desc: "Connection timeout",
+        desc: "Transfer stalled",
         detail: None
     };
 
@@ -197,7 +1334,7 @@ mod test {
         for packet in received.iter() {
             reader_snd.send((LOCALHOST, packet.clone()));
         }
-        let res = get_internal(reader_rcv, writer_snd, LOCALHOST, path, opts, &mut writer);
+        let (res, _) = get_internal(reader_rcv, None, None, writer_snd, LOCALHOST, path, opts, &mut writer);
         println!("result = {}", res);
         let sent = receive_all(&writer_rcv);
         assert_eq!(expected, sent.as_slice());
@@ -244,26 +1381,1183 @@ mod test {
     }
 
     #[test]
-    fn get_timeouts_if_not_receiving_packets() {
-        let res = get_assert_received([], [], [ReadRequest("/path".to_string(), Octet, HashMap::new())]);
-        assert_eq!(Err(ERR_TIMEOUT.clone()), res);
+    fn get_buffers_next_block_received_out_of_order() {
+        let data = gen_data(DEFAULT_BLOCK_SIZE + 10);
+        assert_eq!(get_assert_received(data.as_slice(),
+                                       [Data(2, Vec::from_elem(10, 1u8)),
+                                        Data(1, Vec::from_elem(512, 0u8))],
+                                       [ReadRequest("/path".to_string(), Octet, HashMap::new()),
+                                        Acknowledgment(1),
+                                        Acknowledgment(2)]), Ok(()));
     }
 
-    #[test]
-    fn get_error_on_writing_to_writer() {
+    fn window_options(window_size: &str) -> HashMap<String, String> {
+        let mut topts = HashMap::new();
+        topts.insert("windowsize".to_string(), window_size.to_string());
+        topts
+    }
+
+    #[test]
+    fn get_acknowledges_once_per_window() {
+        let data = gen_data(DEFAULT_BLOCK_SIZE * 7 + 10);
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 2;
+        opts.window_size = 4;
+        let mut received = vec![OptionAcknowledgment(window_options("4"))];
+        for i in range(0u, 7) {
+            received.push(Data(i as u16 + 1, Vec::from_elem(512, i as u8)));
+        }
+        received.push(Data(8, Vec::from_elem(10, 7u8)));
+        assert_eq!(get_assert_received_opts(opts, data.as_slice(), received.as_slice(),
+                                            [ReadRequest("/path".to_string(), Octet, window_options("4")),
+                                             Acknowledgment(0),
+                                             Acknowledgment(4),
+                                             Acknowledgment(8)]), Ok(()));
+    }
+
+    #[test]
+    fn get_acknowledges_every_block_when_window_is_not_acknowledged() {
+        let data = gen_data(DEFAULT_BLOCK_SIZE + 10);
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 2;
+        opts.window_size = 4;
+        assert_eq!(get_assert_received_opts(opts, data.as_slice(),
+                                            [Data(1, Vec::from_elem(512, 0u8)),
+                                             Data(2, Vec::from_elem(10, 1u8))],
+                                            [ReadRequest("/path".to_string(), Octet, window_options("4")),
+                                             Acknowledgment(1),
+                                             Acknowledgment(2)]), Ok(()));
+    }
+
+    #[test]
+    fn get_acknowledges_last_in_order_block_on_window_gap() {
+        let data = gen_data(DEFAULT_BLOCK_SIZE * 2 + 10);
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 2;
+        opts.window_size = 4;
+        assert_eq!(get_assert_received_opts(opts, data.as_slice(),
+                                            [OptionAcknowledgment(window_options("4")),
+                                             Data(1, Vec::from_elem(512, 0u8)),
+                                             Data(4, Vec::from_elem(512, 3u8)),
+                                             Data(2, Vec::from_elem(512, 1u8)),
+                                             Data(3, Vec::from_elem(10, 2u8))],
+                                            [ReadRequest("/path".to_string(), Octet, window_options("4")),
+                                             Acknowledgment(0),
+                                             Acknowledgment(1),
+                                             Acknowledgment(3)]), Ok(()));
+    }
+
+    #[test]
+    fn get_does_not_lock_tid_on_unexpected_first_packet() {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, writer_rcv) = channel();
+        let path = Path::new("/path");
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 10;
+        reader_snd.send((OTHER_PORT, Acknowledgment(1)));
+        reader_snd.send((LOCALHOST, Data(1, Vec::from_elem(10, 0u8))));
+        let mut writer = io::MemWriter::new();
+        let (res, _) = get_internal(reader_rcv, None, None, writer_snd, LOCALHOST, path, opts, &mut writer);
+        assert_eq!(Ok(()), res);
+        assert_eq!(vec![ReadRequest("/path".to_string(), Octet, HashMap::new()),
+                        Acknowledgment(1)], receive_all(&writer_rcv));
+    }
+
+    #[test]
+    fn get_starts_transfer_when_stray_from_other_ip_arrives_first() {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, writer_rcv) = channel();
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 10;
+        let stray = SocketAddr { ip: Ipv4Addr(127, 0, 0, 2), port: 60000 };
+        reader_snd.send((stray, Data(1, Vec::from_elem(10, 1u8))));
+        reader_snd.send((OTHER_PORT, Data(1, Vec::from_elem(10, 0u8))));
+        let mut writer = io::MemWriter::new();
+        let (res, _) = get_internal(reader_rcv, None, None, writer_snd, LOCALHOST, Path::new("/path"), opts, &mut writer);
+        assert_eq!(Ok(()), res);
+        assert_eq!(Vec::from_elem(10, 0u8).as_slice(), writer.get_ref());
+        // The stray gets no reply, the transfer continues with the server's TID
+        let sent: Vec<(SocketAddr, Packet)> = writer_rcv.iter().collect();
+        assert_eq!(vec![(LOCALHOST, ReadRequest("/path".to_string(), Octet, HashMap::new())),
+                        (OTHER_PORT, Acknowledgment(1))], sent);
+    }
+
+    #[test]
+    fn get_surfaces_first_error_from_server_tid() {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, writer_rcv) = channel();
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 1000;
+        reader_snd.send((OTHER_PORT, Error(FileNotFound, "File not found".to_string())));
+        let mut writer = io::MemWriter::new();
+        let (res, _) = get_internal(reader_rcv, None, None, writer_snd, LOCALHOST, Path::new("/path"), opts, &mut writer);
+        let err = res.unwrap_err();
+        assert_eq!("tftp protocol error", err.desc);
+        assert_eq!(Some("File not found".to_string()), err.detail);
+        assert_eq!(vec![ReadRequest("/path".to_string(), Octet, HashMap::new())], receive_all(&writer_rcv));
+    }
+
+    #[test]
+    fn get_ignores_first_error_from_other_ip() {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, _writer_rcv) = channel();
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 10;
+        let stray = SocketAddr { ip: Ipv4Addr(127, 0, 0, 2), port: 60000 };
+        reader_snd.send((stray, Error(FileNotFound, "File not found".to_string())));
+        let mut writer = io::MemWriter::new();
+        let (res, _) = get_internal(reader_rcv, None, None, writer_snd, LOCALHOST, Path::new("/path"), opts, &mut writer);
+        assert_eq!(Err(ERR_NO_RESPONSE.clone()), res);
+    }
+
+    #[test]
+    fn get_rejects_data_block_zero() {
+        let res = get_assert_received([],
+                                      [Data(0, Vec::from_elem(10, 0u8))],
+                                      [ReadRequest("/path".to_string(), Octet, HashMap::new()),
+                                       Error(IllegalOperation, "Invalid data block 0".to_string())]);
+        assert_eq!(io::InvalidInput, res.unwrap_err().kind);
+    }
+
+    #[test]
+    fn get_strict_mode_aborts_on_unknown_opcode_from_peer() {
+        let mut socket = bind_socket(EPHEMERAL).unwrap();
+        let mut peer = bind_socket(EPHEMERAL).unwrap();
+        let client_addr = socket.socket_name().unwrap();
+        let peer_addr = peer.socket_name().unwrap();
+        let (reader_rcv, unknown_rcv) = strict_socket_reader(socket, receive_size(DEFAULT_BLOCK_SIZE));
+        let (writer_snd, writer_rcv) = channel();
+        let data = Packet::encode(Octet, &Data(1, Vec::from_elem(512, 0u8))).unwrap();
+        peer.sendto(data.as_slice(), client_addr).unwrap();
+        peer.sendto([0u8, 0x99, 0, 1], client_addr).unwrap();
+
+        let mut opts: TransferOptions = Default::default();
+        opts.strict = true;
+        let mut writer = io::MemWriter::new();
+        let (res, _) = get_internal(reader_rcv, Some(unknown_rcv), None, writer_snd, peer_addr, Path::new("/path"), opts, &mut writer);
+        assert_eq!(vec![ReadRequest("/path".to_string(), Octet, HashMap::new()),
+                        Acknowledgment(1),
+                        Error(IllegalOperation, "Unknown opcode".to_string())], receive_all(&writer_rcv));
+        assert_eq!(io::InvalidInput, res.unwrap_err().kind);
+    }
+
+    #[test]
+    fn get_uses_transfer_mode_for_request_and_received_data() {
+        let mut peer = bind_socket(EPHEMERAL).unwrap();
+        let peer_addr = peer.socket_name().unwrap();
+        let (seen_snd, seen_rcv) = channel();
+        spawn(proc() {
+            let mut peer = peer;
+            let mut buf = [0u8, ..516];
+            let (addr, request) = receive_packet(&mut peer, Octet, buf).unwrap();
+            peer.sendto([0u8, 3, 0, 1, b'a', b'\r', b'\n', b'b'], addr).unwrap();
+            let (_, ack) = receive_packet(&mut peer, Octet, buf).unwrap();
+            seen_snd.send((request, ack));
+        });
+        let mut opts: TransferOptions = Default::default();
+        opts.mode = NetAscii;
+        let mut writer = io::MemWriter::new();
+        assert_eq!(Ok(()), get(peer_addr, Path::new("/path"), opts, &mut writer));
+        assert_eq!(b"a\nb", writer.get_ref());
+        assert_eq!((ReadRequest("/path".to_string(), NetAscii, HashMap::new()), Acknowledgment(1)),
+                   seen_rcv.recv());
+    }
+
+    #[test]
+    fn put_uses_transfer_mode_for_request_and_sent_data() {
+        let mut peer = bind_socket(EPHEMERAL).unwrap();
+        let peer_addr = peer.socket_name().unwrap();
+        let (seen_snd, seen_rcv) = channel();
+        spawn(proc() {
+            let mut peer = peer;
+            let mut buf = [0u8, ..516];
+            let (addr, request) = receive_packet(&mut peer, Octet, buf).unwrap();
+            send_packet(&mut peer, &addr, Octet, &Acknowledgment(0)).unwrap();
+            let (len, _) = peer.recvfrom(buf).unwrap();
+            send_packet(&mut peer, &addr, Octet, &Acknowledgment(1)).unwrap();
+            seen_snd.send((request, Vec::from_slice(buf.slice_to(len))));
+        });
+        let mut opts: TransferOptions = Default::default();
+        opts.mode = NetAscii;
+        let mut reader = io::BufReader::new(b"a\nb");
+        assert_eq!(Ok(()), put(peer_addr, Path::new("/path"), opts, &mut reader));
+        let (request, datagram) = seen_rcv.recv();
+        assert_eq!(WriteRequest("/path".to_string(), NetAscii, HashMap::new()), request);
+        assert_eq!(b"\x00\x03\x00\x01a\r\nb", datagram.as_slice());
+    }
+
+    #[test]
+    fn get_returns_error_code_sent_by_server() {
+        let mut peer = bind_socket(EPHEMERAL).unwrap();
+        let peer_addr = peer.socket_name().unwrap();
+        spawn(proc() {
+            let mut peer = peer;
+            let mut buf = [0u8, ..516];
+            let (addr, _) = receive_packet(&mut peer, Octet, buf).unwrap();
+            send_packet(&mut peer, &addr, Octet, &Error(FileNotFound, "File not found".to_string())).unwrap();
+        });
+        let mut writer = io::MemWriter::new();
+        assert_eq!(Err(Protocol(FileNotFound, "File not found".to_string())),
+                   get(peer_addr, Path::new("/path"), Default::default(), &mut writer));
+    }
+
+    #[test]
+    fn client_reuses_its_socket_for_sequential_gets() {
+        let mut peer = bind_socket(EPHEMERAL).unwrap();
+        let peer_addr = peer.socket_name().unwrap();
+        let (seen_snd, seen_rcv) = channel();
+        spawn(proc() {
+            let mut peer = peer;
+            let mut buf = [0u8, ..516];
+            for i in range(0u8, 3) {
+                let (addr, _) = receive_packet(&mut peer, Octet, buf).unwrap();
+                seen_snd.send(addr);
+                // Every transfer is answered from a new TID
+                let mut transfer = bind_socket(EPHEMERAL).unwrap();
+                send_packet(&mut transfer, &addr, Octet, &Data(1, vec![i])).unwrap();
+                receive_packet(&mut transfer, Octet, buf).unwrap();
+            }
+        });
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 1000;
+        let mut client = Client::new(peer_addr, opts).unwrap();
+        for i in range(0u8, 3) {
+            let mut writer = io::MemWriter::new();
+            assert_eq!(Ok(()), client.get(Path::new("/path"), &mut writer));
+            assert_eq!(vec![i], writer.unwrap());
+        }
+        let first = seen_rcv.recv();
+        assert_eq!(first, seen_rcv.recv());
+        assert_eq!(first, seen_rcv.recv());
+    }
+
+    #[test]
+    fn get_sends_from_one_socket_and_receives_on_another_when_split() {
+        let mut peer = bind_socket(EPHEMERAL).unwrap();
+        let peer_addr = peer.socket_name().unwrap();
+        let mut send_socket = bind_socket(EPHEMERAL).unwrap();
+        let mut receive_socket = bind_socket(EPHEMERAL).unwrap();
+        let send_addr = send_socket.socket_name().unwrap();
+        let receive_addr = receive_socket.socket_name().unwrap();
+        let (seen_snd, seen_rcv) = channel();
+        spawn(proc() {
+            let mut peer = peer;
+            let mut buf = [0u8, ..516];
+            let (request_addr, _) = receive_packet(&mut peer, Octet, buf).unwrap();
+            // Stands in for a NAT forwarding the reply to the receiving socket
+            send_packet(&mut peer, &receive_addr, Octet, &Data(1, vec![1, 2, 3])).unwrap();
+            let (ack_addr, ack) = receive_packet(&mut peer, Octet, buf).unwrap();
+            seen_snd.send((request_addr, ack_addr, ack));
+        });
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 1000;
+        opts.split_sockets = true;
+        let (reader_recv, unknown_recv, writer_snd) = open_socket_channels(send_socket, receive_socket, &opts);
+        let mut writer = io::MemWriter::new();
+        let (res, _) = get_internal(reader_recv, unknown_recv, None, writer_snd, peer_addr, Path::new("/path"), opts,
+                                    &mut writer);
+        assert_eq!(Ok(()), res);
+        assert_eq!(vec![1u8, 2, 3], writer.unwrap());
+        assert_eq!((send_addr, send_addr, Acknowledgment(1)), seen_rcv.recv());
+    }
+
+    #[test]
+    fn get_returns_timeout_when_server_does_not_reply() {
+        let mut peer = bind_socket(EPHEMERAL).unwrap();
+        let peer_addr = peer.socket_name().unwrap();
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 10;
+        let mut writer = io::MemWriter::new();
+        assert_eq!(Err(Timeout(NoResponse)), get(peer_addr, Path::new("/path"), opts, &mut writer));
+    }
+
+    #[test]
+    fn get_returns_timeout_when_retries_run_out() {
+        let mut peer = bind_socket(EPHEMERAL).unwrap();
+        let peer_addr = peer.socket_name().unwrap();
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 1000;
+        opts.resend_timeout = 2;
+        opts.request_options = Some(HashMap::new());
+        opts.max_retries = 2;
+        let mut writer = io::MemWriter::new();
+        assert_eq!(Err(Timeout(RetriesExhausted)), get(peer_addr, Path::new("/path"), opts, &mut writer));
+    }
+
+    #[test]
+    fn put_returns_timeout_when_transfer_deadline_passes() {
+        let mut peer = bind_socket(EPHEMERAL).unwrap();
+        let peer_addr = peer.socket_name().unwrap();
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 1000;
+        opts.transfer_timeout = Some(10);
+        let data = gen_data(10);
+        let mut reader = io::BufReader::new(data.as_slice());
+        assert_eq!(Err(Timeout(DeadlineExceeded)), put(peer_addr, Path::new("/path"), opts, &mut reader));
+    }
+
+    #[test]
+    fn get_decodes_netascii_line_break_split_between_blocks() {
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 2;
+        opts.mode = NetAscii;
+        let mut first = Vec::from_elem(511, b'a');
+        first.push(b'\r');
+        let mut expected = Vec::from_elem(511, b'a');
+        expected.push_all(b"\nb");
+        assert_eq!(get_assert_received_opts(opts, expected.as_slice(),
+                                            [Data(1, first),
+                                             Data(2, Vec::from_slice(b"\nb"))],
+                                            [ReadRequest("/path".to_string(), NetAscii, HashMap::new()),
+                                             Acknowledgment(1),
+                                             Acknowledgment(2)]), Ok(()));
+    }
+
+    #[test]
+    fn get_decodes_netascii_to_configured_newline() {
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 2;
+        opts.mode = NetAscii;
+        opts.newline = CrLf;
+        assert_eq!(get_assert_received_opts(opts, b"a\r\nb\r\n",
+                                            [Data(1, Vec::from_slice(b"a\r\nb\r\n"))],
+                                            [ReadRequest("/path".to_string(), NetAscii, HashMap::new()),
+                                             Acknowledgment(1)]), Ok(()));
+    }
+
+    #[test]
+    fn get_rejects_netascii_transfer_ending_with_cr() {
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 2;
+        opts.mode = NetAscii;
+        let res = get_assert_received_opts(opts, b"a",
+                                           [Data(1, Vec::from_slice(b"a\r"))],
+                                           [ReadRequest("/path".to_string(), NetAscii, HashMap::new()),
+                                            Acknowledgment(1)]);
+        assert_eq!(io::InvalidInput, res.unwrap_err().kind);
+    }
+
+    #[test]
+    fn get_sends_from_configured_local_address() {
+        let mut peer = bind_socket(EPHEMERAL).unwrap();
+        let peer_addr = peer.socket_name().unwrap();
+        let (seen_snd, seen_rcv) = channel();
+        spawn(proc() {
+            let mut peer = peer;
+            let mut buf = [0u8, ..516];
+            let (addr, _) = receive_packet(&mut peer, Octet, buf).unwrap();
+            peer.sendto([0u8, 3, 0, 1, b'a'], addr).unwrap();
+            seen_snd.send(addr.ip);
+        });
+        let mut opts: TransferOptions = Default::default();
+        opts.local_addr = Ipv4Addr(127, 0, 0, 2);
+        let mut writer = io::MemWriter::new();
+        assert_eq!(Ok(()), get(peer_addr, Path::new("/path"), opts, &mut writer));
+        assert_eq!(Ipv4Addr(127, 0, 0, 2), seen_rcv.recv());
+    }
+
+    #[test]
+    fn get_timeouts_if_not_receiving_packets() {
+        let res = get_assert_received([], [], [ReadRequest("/path".to_string(), Octet, HashMap::new())]);
+        assert_eq!(Err(ERR_NO_RESPONSE.clone()), res);
+    }
+
+    #[test]
+    fn get_aborts_on_deadline_while_packets_keep_arriving() {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, _writer_rcv) = channel();
+        let path = Path::new("/path");
+        let mut opts: TransferOptions = Default::default();
+        opts.transfer_timeout = Some(20);
+        spawn(proc() {
+            loop {
+                if reader_snd.send_opt((LOCALHOST, Acknowledgment(0))).is_err() {
+                    break
+                }
+            }
+        });
+        let mut writer = io::MemWriter::new();
+        let (res, _) = get_internal(reader_rcv, None, None, writer_snd, LOCALHOST, path, opts, &mut writer);
+        assert_eq!(io::TimedOut, res.unwrap_err().kind);
+    }
+
+    #[test]
+    fn get_timeout_distinguishes_no_response_from_stalled_transfer() {
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 2;
+        let (_reader_snd, reader_rcv) = channel();
+        let (writer_snd, _writer_rcv) = channel();
+        let mut writer = io::MemWriter::new();
+        let (res, stats) = get_internal(reader_rcv, None, None, writer_snd, LOCALHOST, Path::new("/path"),
+                                        opts.clone(), &mut writer);
+        assert_eq!(Some(NoResponse), stats.timeout);
+        assert_eq!(Timeout(NoResponse), TftpError::from_transfer(res.unwrap_err(), &stats));
+
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, writer_rcv) = channel();
+        reader_snd.send((LOCALHOST, Data(1, Vec::from_elem(512, 0u8))));
+        let (res, stats) = get_internal(reader_rcv, None, None, writer_snd, LOCALHOST, Path::new("/path"),
+                                        opts, &mut writer);
+        assert_eq!(Some(TransferStalled), stats.timeout);
+        assert_eq!(Timeout(TransferStalled), TftpError::from_transfer(res.unwrap_err(), &stats));
+        assert_eq!(Some(&Error(Undefined, "Transfer stalled".to_string())), receive_all(&writer_rcv).last());
+    }
+
+    #[test]
+    fn get_error_on_writing_to_writer() {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, _writer_rcv) = channel();
+        let path = Path::new("/path");
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 2;
+        let mut buf = [0u8, ..100];
+        let mut writer = io::BufWriter::new(buf);
+        for i in range(1u, 3) {
+            let d = Vec::from_elem(DEFAULT_BLOCK_SIZE, i as u8);
+            reader_snd.send((LOCALHOST, Data(i as u16, d)));
+        }
+        let (res, _) = get_internal(reader_rcv, None, None, writer_snd, LOCALHOST, path, opts, &mut writer);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn get_tells_peer_when_writing_to_writer_fails() {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, writer_rcv) = channel();
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 10;
+        let mut buf = [0u8, ..100];
+        let mut writer = io::BufWriter::new(buf);
+        reader_snd.send((LOCALHOST, Data(1, Vec::from_elem(DEFAULT_BLOCK_SIZE, 1u8))));
+        let (res, _) = get_internal(reader_rcv, None, None, writer_snd, LOCALHOST, Path::new("/path"), opts, &mut writer);
+        let err = res.unwrap_err();
+        assert_eq!(vec![ReadRequest("/path".to_string(), Octet, HashMap::new()),
+                        Error(Undefined, err.desc.to_string())], receive_all(&writer_rcv));
+    }
+
+    struct FailingWriter {
+        err: IoError
+    }
+
+    impl Writer for FailingWriter {
+        fn write(&mut self, _buf: &[u8]) -> IoResult<()> {
+            Err(self.err.clone())
+        }
+    }
+
+    fn get_assert_write_error_sent(err: IoError, code: Error) {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, writer_rcv) = channel();
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 10;
+        reader_snd.send((LOCALHOST, Data(1, Vec::from_elem(10, 1u8))));
+        let mut writer = FailingWriter { err: err.clone() };
+        let (res, _) = get_internal(reader_rcv, None, None, writer_snd, LOCALHOST, Path::new("/path"), opts, &mut writer);
+        assert_eq!(Err(err.clone()), res);
+        assert_eq!(vec![ReadRequest("/path".to_string(), Octet, HashMap::new()),
+                        Error(code, err.desc.to_string())], receive_all(&writer_rcv));
+    }
+
+    #[test]
+    fn get_reports_permission_denied_writer_as_access_violation() {
+        get_assert_write_error_sent(io::standard_error(io::PermissionDenied), AccessViolation);
+    }
+
+    #[test]
+    fn get_reports_full_disk_as_disk_full() {
+        get_assert_write_error_sent(io::standard_error(io::ShortWrite(0)), DiskFull);
+    }
+
+    #[test]
+    fn get_reports_partial_write_as_undefined() {
+        get_assert_write_error_sent(io::standard_error(io::ShortWrite(3)), Undefined);
+    }
+
+    #[test]
+    fn get_tells_peer_when_netascii_decoding_fails() {
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 2;
+        opts.mode = NetAscii;
+        let res = get_assert_received_opts(opts, b"",
+                                           [Data(1, Vec::from_slice(b"a\r\x41"))],
+                                           [ReadRequest("/path".to_string(), NetAscii, HashMap::new()),
+                                            Error(Undefined, "Invalid netascii encoding".to_string())]);
+        assert_eq!(io::InvalidInput, res.unwrap_err().kind);
+    }
+
+    struct BlockingWriter {
+        unblock: Receiver<()>
+    }
+
+    impl Writer for BlockingWriter {
+        fn write(&mut self, _buf: &[u8]) -> IoResult<()> {
+            let _ = self.unblock.recv_opt();
+            Ok(())
+        }
+    }
+
+    struct GatedWriter {
+        gate: Receiver<()>,
+        written: Sender<Vec<u8>>
+    }
+
+    impl Writer for GatedWriter {
+        fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+            let _ = self.gate.recv_opt();
+            self.written.send(Vec::from_slice(buf));
+            Ok(())
+        }
+    }
+
+    struct WouldBlockOnceWriter {
+        blocked: bool,
+        written: Vec<u8>
+    }
+
+    impl Writer for WouldBlockOnceWriter {
+        fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+            if !self.blocked {
+                self.blocked = true;
+                return Err(io::standard_error(io::ResourceUnavailable))
+            }
+            self.written.push_all(buf);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn get_waits_for_retransmit_when_writer_would_block() {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, writer_rcv) = channel();
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 10;
+        opts.retry_would_block = true;
+        reader_snd.send((LOCALHOST, Data(1, Vec::from_elem(10, 0u8))));
+        reader_snd.send((LOCALHOST, Data(1, Vec::from_elem(10, 0u8))));
+        let mut writer = WouldBlockOnceWriter { blocked: false, written: Vec::new() };
+        let (res, _) = get_internal(reader_rcv, None, None, writer_snd, LOCALHOST, Path::new("/path"), opts, &mut writer);
+        assert_eq!(Ok(()), res);
+        assert_eq!(Vec::from_elem(10, 0u8), writer.written);
+        assert_eq!(vec![ReadRequest("/path".to_string(), Octet, HashMap::new()),
+                        Acknowledgment(1)], receive_all(&writer_rcv));
+    }
+
+    struct ShortWriter {
+        limit: uint,
+        written: Vec<u8>
+    }
+
+    impl Writer for ShortWriter {
+        fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+            let len = ::std::cmp::min(buf.len(), self.limit);
+            self.written.push_all(buf.slice_to(len));
+            if len < buf.len() {
+                Err(IoError { kind: io::ShortWrite(len), desc: "Short write", detail: None })
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    #[test]
+    fn get_writes_whole_block_to_writer_accepting_few_bytes() {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, writer_rcv) = channel();
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 10;
+        let data = Vec::from_fn(100, |i| i as u8);
+        reader_snd.send((LOCALHOST, Data(1, data.clone())));
+        let mut writer = ShortWriter { limit: 3, written: Vec::new() };
+        let (res, _) = get_internal(reader_rcv, None, None, writer_snd, LOCALHOST, Path::new("/path"), opts, &mut writer);
+        assert_eq!(Ok(()), res);
+        assert_eq!(data, writer.written);
+        assert_eq!(vec![ReadRequest("/path".to_string(), Octet, HashMap::new()),
+                        Acknowledgment(1)], receive_all(&writer_rcv));
+    }
+
+    #[test]
+    fn get_times_out_when_writer_task_is_stuck() {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, writer_rcv) = channel();
+        let (_unblock_snd, unblock_rcv) = channel();
+        let path = Path::new("/path");
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 20;
+        // As set by get_with_writer_task
+        opts.retry_would_block = true;
+        for i in range(1u, 5) {
+            let d = Vec::from_elem(DEFAULT_BLOCK_SIZE, i as u8);
+            reader_snd.send((LOCALHOST, Data(i as u16, d)));
+        }
+        let mut writer = TaskWriter::spawn(box BlockingWriter { unblock: unblock_rcv }, 1);
+        let (res, stats) = get_internal(reader_rcv, None, None, writer_snd, LOCALHOST, path, opts, &mut writer);
+        assert_eq!(Some(TransferStalled), stats.timeout);
+        assert_eq!(Timeout(TransferStalled), TftpError::from_transfer(res.unwrap_err(), &stats));
+        assert_eq!(Some(&Error(Undefined, "Transfer stalled".to_string())), receive_all(&writer_rcv).last());
+    }
+
+    #[test]
+    fn get_waits_for_retransmit_while_writer_task_queue_is_full() {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, _writer_rcv) = channel();
+        let (gate_snd, gate_rcv) = channel();
+        let (written_snd, written_rcv) = channel();
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 1000;
+        opts.retry_would_block = true;
+        let blocks: Vec<Vec<u8>> = range(1u, 4).map(|i| Vec::from_elem(DEFAULT_BLOCK_SIZE, i as u8)).collect();
+        for (i, block) in blocks.iter().enumerate() {
+            reader_snd.send((LOCALHOST, Data(i as u16 + 1, block.clone())));
+        }
+        let retransmitted = blocks.clone();
+        spawn(proc() {
+            timer::sleep(50);
+            for _ in range(0u, 3) {
+                let _ = gate_snd.send_opt(());
+            }
+            // The peer retransmits what was not acknowledged
+            for _ in range(0u, 3) {
+                timer::sleep(10);
+                for (i, block) in retransmitted.iter().enumerate().skip(1) {
+                    let _ = reader_snd.send_opt((LOCALHOST, Data(i as u16 + 1, block.clone())));
+                }
+            }
+            let _ = reader_snd.send_opt((LOCALHOST, Data(4, Vec::new())));
+        });
+        let mut writer = TaskWriter::spawn(box GatedWriter { gate: gate_rcv, written: written_snd }, 1);
+        let (res, _) = get_internal(reader_rcv, None, None, writer_snd, LOCALHOST, Path::new("/path"), opts, &mut writer);
+        assert_eq!(Ok(()), res);
+        assert_eq!(Ok(()), writer.finish());
+        let written: Vec<Vec<u8>> = written_rcv.iter().filter(|block| !block.is_empty()).collect();
+        assert_eq!(blocks, written);
+    }
+
+    #[test]
+    fn get_reports_bytes_written_before_failure() {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, _writer_rcv) = channel();
+        let path = Path::new("/path");
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 2;
+        let mut buf = [0u8, ..600];
+        let mut writer = io::BufWriter::new(buf);
+        for i in range(1u, 4) {
+            let d = Vec::from_elem(DEFAULT_BLOCK_SIZE, i as u8);
+            reader_snd.send((LOCALHOST, Data(i as u16, d)));
+        }
+        let (res, stats) = get_internal(reader_rcv, None, None, writer_snd, LOCALHOST, path, opts, &mut writer);
+        assert!(res.is_err());
+        assert_eq!(DEFAULT_BLOCK_SIZE as u64, stats.transferred);
+    }
+
+    #[test]
+    fn get_answers_retransmits_while_paused_and_completes_after_resume() {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, writer_rcv) = channel();
+        let (control_snd, control_rcv) = channel();
+        let (sent_snd, sent_rcv) = channel();
+        spawn(proc() {
+            let mut sent = Vec::new();
+            for (_, packet) in writer_rcv.iter() {
+                match packet {
+                    ReadRequest(..) => {
+                        reader_snd.send((LOCALHOST, Data(1, Vec::from_elem(512, 0u8))));
+                    }
+                    Acknowledgment(1) if sent.len() == 1 => {
+                        // The ack is lost, the block is retransmitted while paused
+                        control_snd.send(Pause);
+                        reader_snd.send((LOCALHOST, Data(1, Vec::from_elem(512, 0u8))));
+                    }
+                    Acknowledgment(1) => {
+                        control_snd.send(Resume);
+                        reader_snd.send((LOCALHOST, Data(2, Vec::from_elem(10, 1u8))));
+                    }
+                    _ => {}
+                }
+                sent.push(packet);
+            }
+            sent_snd.send(sent);
+        });
+        let mut writer = io::MemWriter::new();
+        let opts: TransferOptions = Default::default();
+        let (res, _) = get_internal(reader_rcv, None, Some(control_rcv), writer_snd, LOCALHOST,
+                                    Path::new("/path"), opts, &mut writer);
+        assert_eq!(Ok(()), res);
+        assert_eq!(gen_data(DEFAULT_BLOCK_SIZE + 10).as_slice(), writer.get_ref());
+        assert_eq!(vec![ReadRequest("/path".to_string(), Octet, HashMap::new()),
+                        Acknowledgment(1),
+                        Acknowledgment(1),
+                        Acknowledgment(2)], sent_rcv.recv());
+    }
+
+    #[test]
+    fn get_decompresses_payload_when_compression_is_acknowledged() {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, _writer_rcv) = channel();
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 10;
+        opts.compress = true;
+        let mut topts = HashMap::new();
+        topts.insert("compress".to_string(), "gzip".to_string());
+        reader_snd.send((LOCALHOST, OptionAcknowledgment(topts)));
+        let data = gen_data(DEFAULT_BLOCK_SIZE * 20);
+        let compressed = gzip::compress(data.as_slice()).unwrap();
+        for (i, block) in compressed.as_slice().chunks(DEFAULT_BLOCK_SIZE).enumerate() {
+            reader_snd.send((LOCALHOST, Data(i as u16 + 1, Vec::from_slice(block))));
+        }
+        if compressed.len() % DEFAULT_BLOCK_SIZE == 0 {
+            let last = compressed.len() / DEFAULT_BLOCK_SIZE + 1;
+            reader_snd.send((LOCALHOST, Data(last as u16, Vec::new())));
+        }
+        let mut writer = io::MemWriter::new();
+        let (res, _) = get_internal(reader_rcv, None, None, writer_snd, LOCALHOST, Path::new("/path"), opts, &mut writer);
+        assert_eq!(Ok(()), res);
+        assert_eq!(data.as_slice(), writer.get_ref());
+    }
+
+    fn gen_text(len: uint) -> Vec<u8> {
+        Vec::from_fn(len, |i| match i % 13 {
+            0 => b'\n',
+            7 => b'\r',
+            n => b'a' + n as u8
+        })
+    }
+
+    #[test]
+    fn get_decodes_netascii_after_decompressing_payload() {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, _writer_rcv) = channel();
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 10;
+        opts.mode = NetAscii;
+        opts.compress = true;
+        let mut topts = HashMap::new();
+        topts.insert("compress".to_string(), "gzip".to_string());
+        reader_snd.send((LOCALHOST, OptionAcknowledgment(topts)));
+        let data = gen_text(DEFAULT_BLOCK_SIZE * 20);
+        let encoded = NetasciiReader::new(io::BufReader::new(data.as_slice())).read_to_end().unwrap();
+        let compressed = gzip::compress(encoded.as_slice()).unwrap();
+        for (i, block) in compressed.as_slice().chunks(DEFAULT_BLOCK_SIZE).enumerate() {
+            reader_snd.send((LOCALHOST, Data(i as u16 + 1, Vec::from_slice(block))));
+        }
+        if compressed.len() % DEFAULT_BLOCK_SIZE == 0 {
+            let last = compressed.len() / DEFAULT_BLOCK_SIZE + 1;
+            reader_snd.send((LOCALHOST, Data(last as u16, Vec::new())));
+        }
+        let mut writer = io::MemWriter::new();
+        let (res, _) = get_internal(reader_rcv, None, None, writer_snd, LOCALHOST, Path::new("/path"), opts, &mut writer);
+        assert_eq!(Ok(()), res);
+        assert_eq!(data.as_slice(), writer.get_ref());
+    }
+
+    #[test]
+    fn get_leaves_block_with_bad_checksum_for_retransmission() {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, writer_rcv) = channel();
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 10;
+        opts.crc = true;
+        let mut topts = HashMap::new();
+        topts.insert("crc".to_string(), "crc16".to_string());
+        reader_snd.send((LOCALHOST, OptionAcknowledgment(topts)));
+        let mut block = gen_data(10);
+        append_crc(&mut block);
+        let mut corrupted = block.clone();
+        *corrupted.get_mut(0) ^= 1;
+        reader_snd.send((LOCALHOST, Data(1, corrupted)));
+        reader_snd.send((LOCALHOST, Data(1, block)));
+        let mut writer = io::MemWriter::new();
+        let (res, _) = get_internal(reader_rcv, None, None, writer_snd, LOCALHOST, Path::new("/path"), opts.clone(), &mut writer);
+        assert_eq!(Ok(()), res);
+        assert_eq!(gen_data(10).as_slice(), writer.get_ref());
+        assert_eq!(vec![ReadRequest("/path".to_string(), Octet, opts.to_options()),
+                        Acknowledgment(0),
+                        Acknowledgment(1)], receive_all(&writer_rcv));
+    }
+
+    #[test]
+    fn put_reports_progress_after_each_acknowledged_block() {
+        let data = gen_data(2 * DEFAULT_BLOCK_SIZE + 76);
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 10;
+        opts.transfer_size = Some(data.len() as u64);
+        let mut topts = HashMap::new();
+        topts.insert("tsize".to_string(), data.len().to_str());
+
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, _writer_rcv) = channel();
+        reader_snd.send((LOCALHOST, OptionAcknowledgment(topts)));
+        for i in range(1u16, 4) {
+            reader_snd.send((LOCALHOST, Acknowledgment(i)));
+        }
+        let mut reader = io::BufReader::new(data.as_slice());
+        let mut retry = FixedRetry::new(opts.resend_timeout);
+        let mut reported = Vec::new();
+        let (res, _) = put_internal(reader_rcv, None, writer_snd, LOCALHOST, Path::new("/path"),
+                                    opts, &mut retry,
+                                    TransferHooks::new().progress(|transferred, total| {
+                                        reported.push((transferred, total))
+                                    }), &mut reader);
+        assert_eq!(Ok(()), res);
+        let total = Some(data.len() as u64);
+        assert_eq!(vec![(512, total), (1024, total), (1100, total)], reported);
+    }
+
+    #[test]
+    fn put_appends_checksum_when_crc_is_acknowledged() {
+        let data = gen_data(DEFAULT_BLOCK_SIZE);
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 10;
+        opts.crc = true;
+        let mut topts = HashMap::new();
+        topts.insert("crc".to_string(), "crc16".to_string());
+
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, writer_rcv) = channel();
+        reader_snd.send((LOCALHOST, OptionAcknowledgment(topts)));
+        reader_snd.send((LOCALHOST, Acknowledgment(1)));
+        reader_snd.send((LOCALHOST, Acknowledgment(2)));
+        let mut reader = io::BufReader::new(data.as_slice());
+        let mut retry = FixedRetry::new(opts.resend_timeout);
+        let (res, _) = put_internal(reader_rcv, None, writer_snd, LOCALHOST, Path::new("/path"),
+                                    opts.clone(), &mut retry, TransferHooks::new(), &mut reader);
+        assert_eq!(Ok(()), res);
+        let mut first = Vec::from_slice(data.slice_to(DEFAULT_BLOCK_SIZE - CRC_SIZE));
+        append_crc(&mut first);
+        let mut second = Vec::from_slice(data.slice_from(DEFAULT_BLOCK_SIZE - CRC_SIZE));
+        append_crc(&mut second);
+        assert_eq!(vec![WriteRequest("/path".to_string(), Octet, opts.to_options()),
+                        Data(1, first),
+                        Data(2, second)], receive_all(&writer_rcv));
+    }
+
+    #[test]
+    fn get_sends_raw_request_options_verbatim() {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, writer_rcv) = channel();
+        let mut raw = HashMap::new();
+        raw.insert("BlkSize".to_string(), "1024".to_string());
+        raw.insert("x-vendor".to_string(), "".to_string());
+        let mut opts = TransferOptions::from_map(&raw);
+        opts.receive_timeout = 10;
+        opts.request_options = Some(raw.clone());
+        let mut topts = HashMap::new();
+        topts.insert("blksize".to_string(), "1024".to_string());
+        reader_snd.send((LOCALHOST, OptionAcknowledgment(topts)));
+        reader_snd.send((LOCALHOST, Data(1, gen_data(1024))));
+        reader_snd.send((LOCALHOST, Data(2, gen_data(10))));
+        let mut writer = io::MemWriter::new();
+        let (res, _) = get_internal(reader_rcv, None, None, writer_snd, LOCALHOST, Path::new("/path"), opts, &mut writer);
+        assert_eq!(Ok(()), res);
+        assert_eq!(1034, writer.get_ref().len());
+        assert_eq!(vec![ReadRequest("/path".to_string(), Octet, raw),
+                        Acknowledgment(0),
+                        Acknowledgment(1),
+                        Acknowledgment(2)], receive_all(&writer_rcv));
+    }
+
+    #[test]
+    fn get_summarizes_finished_transfer() {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, _writer_rcv) = channel();
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 10;
+        opts.block_size = 1024;
+        let mut topts = HashMap::new();
+        topts.insert("blksize".to_string(), "1024".to_string());
+        reader_snd.send((LOCALHOST, OptionAcknowledgment(topts)));
+        reader_snd.send((LOCALHOST, Data(1, gen_data(1024))));
+        reader_snd.send((LOCALHOST, Data(2, gen_data(10))));
+        let mut writer = io::MemWriter::new();
+        let (res, stats) = get_internal(reader_rcv, None, None, writer_snd, LOCALHOST, Path::new("/path"), opts, &mut writer);
+        assert_eq!(Ok(()), res);
+        let summary = stats.summary.unwrap();
+        assert_eq!("/path".to_string(), summary.filename);
+        assert_eq!(Octet, summary.mode);
+        assert_eq!(1024, summary.block_size);
+        assert_eq!(1034, summary.transferred);
+        assert_eq!(0, summary.resends);
+        assert!(summary.elapsed > 0);
+        assert_eq!(Ok(()), summary.status);
+    }
+
+    #[test]
+    fn get_reports_progress_after_each_block() {
         let (reader_snd, reader_rcv) = channel();
         let (writer_snd, _writer_rcv) = channel();
-        let path = Path::new("/path");
         let mut opts: TransferOptions = Default::default();
-        opts.receive_timeout = 2;
-        let mut buf = [0u8, ..100];
-        let mut writer = io::BufWriter::new(buf);
-        for i in range(1u, 3) {
-            let d = Vec::from_elem(DEFAULT_BLOCK_SIZE, i as u8);
-            reader_snd.send((LOCALHOST, Data(i as u16, d)));
+        opts.receive_timeout = 10;
+        opts.transfer_size = Some(0);
+        let data = gen_data(2 * DEFAULT_BLOCK_SIZE + 76);
+        let mut topts = HashMap::new();
+        topts.insert("tsize".to_string(), data.len().to_str());
+        reader_snd.send((LOCALHOST, OptionAcknowledgment(topts)));
+        for (i, block) in data.as_slice().chunks(DEFAULT_BLOCK_SIZE).enumerate() {
+            reader_snd.send((LOCALHOST, Data(i as u16 + 1, Vec::from_slice(block))));
+        }
+        let mut reported = Vec::new();
+        let mut writer = io::MemWriter::new();
+        let (res, _) = get_internal_attempts(reader_rcv, None, writer_snd, LOCALHOST, Path::new("/path"),
+                                             opts, 1, TransferHooks::new().progress(|transferred, total| {
+                                                 reported.push((transferred, total))
+                                             }), &mut writer);
+        assert_eq!(Ok(()), res);
+        let total = Some(data.len() as u64);
+        assert_eq!(vec![(512, total), (1024, total), (1100, total)], reported);
+    }
+
+    #[test]
+    fn get_aborts_when_peer_keeps_repeating_a_block() {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, _writer_rcv) = channel();
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 1000;
+        for _ in range(0u, MAX_REPEATED_BLOCKS + 5) {
+            reader_snd.send((LOCALHOST, Data(1, gen_data(DEFAULT_BLOCK_SIZE))));
+        }
+        let mut writer = io::MemWriter::new();
+        let (res, _) = get_internal(reader_rcv, None, None, writer_snd, LOCALHOST, Path::new("/path"), opts, &mut writer);
+        let err = res.unwrap_err();
+        assert_eq!(io::ConnectionAborted, err.kind);
+        assert_eq!("Peer is not advancing", err.desc);
+        assert_eq!(gen_data(DEFAULT_BLOCK_SIZE).as_slice(), writer.get_ref());
+    }
+
+    #[test]
+    fn get_reacknowledges_repeated_block_until_peer_is_considered_stuck() {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, writer_rcv) = channel();
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 1000;
+        for _ in range(0u, MAX_REPEATED_BLOCKS + 1) {
+            reader_snd.send((LOCALHOST, Data(1, gen_data(DEFAULT_BLOCK_SIZE))));
+        }
+        let mut writer = io::MemWriter::new();
+        let (res, _) = get_internal(reader_rcv, None, None, writer_snd, LOCALHOST, Path::new("/path"), opts, &mut writer);
+        let err = res.unwrap_err();
+        assert_eq!("Peer is not advancing", err.desc);
+        assert_eq!(Some(format!("block 1 received {} more times despite being acknowledged", MAX_REPEATED_BLOCKS)),
+                   err.detail);
+        let mut expected = vec![ReadRequest("/path".to_string(), Octet, HashMap::new())];
+        for _ in range(0u, MAX_REPEATED_BLOCKS) {
+            expected.push(Acknowledgment(1));
+        }
+        expected.push(Error(Undefined, "Acknowledgments are not getting through".to_string()));
+        assert_eq!(expected, receive_all(&writer_rcv));
+    }
+
+    #[test]
+    fn get_aborts_after_max_blocks() {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, writer_rcv) = channel();
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 1000;
+        opts.block_size = 8;
+        opts.max_blocks = Some(3);
+        let mut topts = HashMap::new();
+        topts.insert("blksize".to_string(), "8".to_string());
+        reader_snd.send((LOCALHOST, OptionAcknowledgment(topts.clone())));
+        for i in range(1u16, 6) {
+            reader_snd.send((LOCALHOST, Data(i, Vec::from_elem(8, i as u8))));
         }
-        let res = get_internal(reader_rcv, writer_snd, LOCALHOST, path, opts, &mut writer);
+        let mut writer = io::MemWriter::new();
+        let (res, stats) = get_internal(reader_rcv, None, None, writer_snd, LOCALHOST, Path::new("/path"), opts, &mut writer);
+        let err = res.unwrap_err();
+        assert_eq!(io::ConnectionAborted, err.kind);
+        assert_eq!("Too many blocks", err.desc);
+        assert_eq!(24, stats.transferred);
+        assert_eq!(vec![ReadRequest("/path".to_string(), Octet, topts),
+                        Acknowledgment(0),
+                        Acknowledgment(1),
+                        Acknowledgment(2),
+                        Acknowledgment(3),
+                        Error(Undefined, "too many blocks".to_string())], receive_all(&writer_rcv));
+    }
+
+    #[test]
+    fn get_fails_cleanly_when_socket_reader_terminates() {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, _writer_rcv) = channel();
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 1000;
+        reader_snd.send((LOCALHOST, Data(1, gen_data(DEFAULT_BLOCK_SIZE))));
+        drop(reader_snd);
+        let mut writer = io::MemWriter::new();
+        let (res, _) = get_internal(reader_rcv, None, None, writer_snd, LOCALHOST, Path::new("/path"), opts, &mut writer);
+        let err = res.unwrap_err();
+        assert_eq!(io::ConnectionAborted, err.kind);
+        assert_eq!("Socket reader terminated", err.desc);
+        assert_eq!(gen_data(DEFAULT_BLOCK_SIZE).as_slice(), writer.get_ref());
+    }
+
+    #[test]
+    fn get_buffered_writes_nothing_when_transfer_fails() {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, _writer_rcv) = channel();
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 10;
+        reader_snd.send((LOCALHOST, Data(1, gen_data(DEFAULT_BLOCK_SIZE))));
+        let mut writer = io::MemWriter::new();
+        let res = get_buffered_internal(reader_rcv, None, writer_snd, LOCALHOST, Path::new("/path"), opts, &mut writer);
         assert!(res.is_err());
+        assert!(writer.get_ref().is_empty());
+    }
+
+    #[test]
+    fn get_fails_when_received_size_differs_from_acknowledged_tsize() {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, _writer_rcv) = channel();
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 10;
+        opts.transfer_size = Some(0);
+        let mut topts = HashMap::new();
+        topts.insert("tsize".to_string(), "600".to_string());
+        reader_snd.send((LOCALHOST, OptionAcknowledgment(topts)));
+        reader_snd.send((LOCALHOST, Data(1, gen_data(DEFAULT_BLOCK_SIZE))));
+        reader_snd.send((LOCALHOST, Data(2, Vec::new())));
+        let mut writer = io::MemWriter::new();
+        let (res, stats) = get_internal(reader_rcv, None, None, writer_snd, LOCALHOST, Path::new("/path"), opts, &mut writer);
+        let err = res.unwrap_err();
+        assert_eq!("Transfer size does not match the advertised tsize", err.desc);
+        assert_eq!(Some("advertised 600 bytes, received 512".to_string()), err.detail);
+        assert_eq!(512, stats.transferred);
+    }
+
+    #[test]
+    fn get_buffered_checks_acknowledged_size() {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, _writer_rcv) = channel();
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 10;
+        opts.transfer_size = Some(0);
+        let mut topts = HashMap::new();
+        topts.insert("tsize".to_string(), "20".to_string());
+        reader_snd.send((LOCALHOST, OptionAcknowledgment(topts)));
+        reader_snd.send((LOCALHOST, Data(1, gen_data(10))));
+        let mut writer = io::MemWriter::new();
+        let res = get_buffered_internal(reader_rcv, None, writer_snd, LOCALHOST, Path::new("/path"), opts, &mut writer);
+        match res {
+            Err(Io(err)) => assert_eq!("Transfer size does not match the advertised tsize", err.desc),
+            res => fail!("unexpected result {}", res)
+        }
+        assert!(writer.get_ref().is_empty());
+    }
+
+    #[test]
+    fn get_starts_over_after_failed_attempt() {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, writer_rcv) = channel();
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 10;
+        reader_snd.send((OTHER_PORT, Data(0, Vec::from_elem(10, 0u8))));
+        reader_snd.send((LOCALHOST, Data(1, Vec::from_elem(10, 1u8))));
+        let mut writer = io::MemWriter::new();
+        let (res, _) = get_internal_attempts(reader_rcv, None, writer_snd, LOCALHOST, Path::new("/path"),
+                                             opts, 2, TransferHooks::new(), &mut writer);
+        assert_eq!(Ok(()), res);
+        assert_eq!(Vec::from_elem(10, 1u8).as_slice(), writer.get_ref());
+        let sent: Vec<(SocketAddr, Packet)> = writer_rcv.iter().collect();
+        assert_eq!(vec![(LOCALHOST, ReadRequest("/path".to_string(), Octet, HashMap::new())),
+                        (OTHER_PORT, Error(IllegalOperation, "Invalid data block 0".to_string())),
+                        (LOCALHOST, ReadRequest("/path".to_string(), Octet, HashMap::new())),
+                        (LOCALHOST, Acknowledgment(1))], sent);
+    }
+
+    #[test]
+    fn get_reports_all_acknowledged_options() {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, _writer_rcv) = channel();
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 10;
+        opts.block_size = 1024;
+        let mut topts = HashMap::new();
+        topts.insert("blksize".to_string(), "1024".to_string());
+        topts.insert("utimeout".to_string(), "100".to_string());
+        reader_snd.send((LOCALHOST, OptionAcknowledgment(topts.clone())));
+        reader_snd.send((LOCALHOST, Data(1, Vec::from_elem(10, 0u8))));
+        let mut writer = io::MemWriter::new();
+        let (res, stats) = get_internal(reader_rcv, None, None, writer_snd, LOCALHOST, Path::new("/path"), opts.clone(), &mut writer);
+        assert_eq!(Ok(()), res);
+        assert_eq!(Some(topts.clone()), stats.acknowledged_options);
+        let effective = opts.negotiate(&topts).to_options();
+        assert_eq!(Some(&"1024".to_string()), effective.find(&"blksize".to_string()));
+        assert_eq!(None, effective.find(&"utimeout".to_string()));
+    }
+
+    #[test]
+    fn get_reports_round_trip_time_estimate() {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, _writer_rcv) = channel();
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 2;
+        let mut writer = io::MemWriter::new();
+        reader_snd.send((LOCALHOST, Data(1, Vec::from_elem(10, 0u8))));
+        let (res, stats) = get_internal(reader_rcv, None, None, writer_snd, LOCALHOST, Path::new("/path"), opts, &mut writer);
+        assert_eq!(Ok(()), res);
+        assert!(stats.rtt.is_some());
+    }
+
+    #[test]
+    fn get_reacknowledges_final_block_retransmitted_while_dallying() {
+        let data = gen_data(10);
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 2;
+        opts.dally_timeout = 20;
+        assert_eq!(get_assert_received_opts(opts, data.as_slice(),
+                                            [Data(1, Vec::from_elem(10, 0u8)),
+                                             Data(1, Vec::from_elem(10, 0u8)),
+                                             Data(1, Vec::from_elem(10, 0u8))],
+                                            [ReadRequest("/path".to_string(), Octet, HashMap::new()),
+                                             Acknowledgment(1),
+                                             Acknowledgment(1),
+                                             Acknowledgment(1)]), Ok(()));
+    }
+
+    #[test]
+    fn get_tee_writes_blocks_to_all_writers() {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, _writer_rcv) = channel();
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 2;
+        reader_snd.send((LOCALHOST, Data(1, Vec::from_elem(512, 0u8))));
+        reader_snd.send((LOCALHOST, Data(2, Vec::from_elem(10, 1u8))));
+
+        let mut first = io::MemWriter::new();
+        let mut second = io::MemWriter::new();
+        {
+            let mut writers = [&mut first as &mut Writer, &mut second as &mut Writer];
+            let mut tee = TeeWriter { writers: writers.as_mut_slice() };
+            let (res, _) = get_internal(reader_rcv, None, None, writer_snd, LOCALHOST, Path::new("/path"), opts, &mut tee);
+            assert_eq!(Ok(()), res);
+        }
+        assert_eq!(gen_data(DEFAULT_BLOCK_SIZE + 10).as_slice(), first.get_ref());
+        assert_eq!(first.get_ref(), second.get_ref());
+    }
+
+    #[test]
+    fn get_records_exchanged_packets() {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, _writer_rcv) = channel();
+        let path = Path::new("/path");
+        let mut opts: TransferOptions = Default::default();
+        opts.record = true;
+        let received = [Data(1, Vec::from_elem(512, 0u8)), Data(2, Vec::from_elem(10, 1u8))];
+        for packet in received.iter() {
+            reader_snd.send((LOCALHOST, packet.clone()));
+        }
+        let mut writer = io::MemWriter::new();
+        let (res, stats) = get_internal(reader_rcv, None, None, writer_snd, LOCALHOST, path, opts, &mut writer);
+        assert_eq!(Ok(()), res);
+
+        let recording = stats.recording.unwrap();
+        let packets: Vec<(Direction, Packet)> = recording.iter().map(|&(_, d, ref p)| (d, p.clone())).collect();
+        assert_eq!(vec![(Sent, ReadRequest("/path".to_string(), Octet, HashMap::new())),
+                        (Received, received[0].clone()),
+                        (Sent, Acknowledgment(1)),
+                        (Received, received[1].clone()),
+                        (Sent, Acknowledgment(2))], packets);
+        let times: Vec<u64> = recording.iter().map(|&(t, _, _)| t).collect();
+        assert!(times.as_slice().windows(2).all(|w| w[0] <= w[1]));
     }
 
     #[test]
@@ -309,7 +2603,7 @@ mod test {
         expected.push(Acknowledgment(0 as u16));
         expected.push(Acknowledgment(1 as u16));
 
-        let res = get_internal(reader_rcv, writer_snd, LOCALHOST, path, opts, &mut writer);
+        let (res, _) = get_internal(reader_rcv, None, None, writer_snd, LOCALHOST, path, opts, &mut writer);
         println!("result = {}", res);
         let sent = receive_all(&writer_rcv);
         for (e, s) in expected.iter().zip(sent.iter()) {
@@ -328,7 +2622,7 @@ mod test {
         static MAX: uint = ::std::u16::MAX as uint;
         let mut opts: TransferOptions = Default::default();
         opts.block_size = 1;
-        opts.rollover = Some(One);
+        opts.rollover = ToOne;
 
         let mut topts = HashMap::new();
         topts.insert("blksize".to_string(), 1u.to_str());
@@ -349,7 +2643,7 @@ mod test {
         expected.push(Acknowledgment(1 as u16));
         expected.push(Acknowledgment(2 as u16));
 
-        let res = get_internal(reader_rcv, writer_snd, LOCALHOST, path, opts, &mut writer);
+        let (res, _) = get_internal(reader_rcv, None, None, writer_snd, LOCALHOST, path, opts, &mut writer);
         println!("result = {}", res);
         let sent = receive_all(&writer_rcv);
         for (e, s) in expected.iter().zip(sent.iter()) {
@@ -359,6 +2653,42 @@ mod test {
         assert_eq!(Ok(()), res);
     }
 
+    #[test]
+    fn get_acknowledges_repeated_block_before_rollover_to_one() {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, writer_rcv) = channel();
+        let path = Path::new("/path");
+
+        static MAX: uint = ::std::u16::MAX as uint;
+        let mut opts: TransferOptions = Default::default();
+        opts.block_size = 1;
+        opts.rollover = ToOne;
+
+        let mut topts = HashMap::new();
+        topts.insert("blksize".to_string(), 1u.to_str());
+        topts.insert("rollover".to_string(), 1u.to_str());
+
+        let mut writer = io::MemWriter::new();
+        reader_snd.send((LOCALHOST, OptionAcknowledgment(topts.clone())));
+        for i in range(1, MAX + 1) {
+            reader_snd.send((LOCALHOST, Data(i as u16, Vec::from_slice([0u8]))));
+        }
+        reader_snd.send((LOCALHOST, Data(MAX as u16, Vec::from_slice([0u8]))));
+        reader_snd.send((LOCALHOST, Data(1, Vec::from_slice([]))));
+
+        let mut expected = Vec::from_slice([ReadRequest("/path".to_string(), Octet, topts)]);
+        for i in range(0, MAX + 1) {
+            expected.push(Acknowledgment(i as u16));
+        }
+        expected.push(Acknowledgment(MAX as u16));
+        expected.push(Acknowledgment(1));
+
+        let (res, _) = get_internal(reader_rcv, None, None, writer_snd, LOCALHOST, path, opts, &mut writer);
+        assert_eq!(Ok(()), res);
+        assert_eq!(expected, receive_all(&writer_rcv));
+        assert_eq!(MAX, writer.get_ref().len());
+    }
+
     #[test]
     fn get_non_default_options_are_sent_in_request() {
         let data = gen_data(0);
@@ -367,7 +2697,7 @@ mod test {
         opts.transfer_size = Some(0);
         opts.receive_timeout = 20;
         opts.resend_timeout = 11;
-        opts.rollover = Some(Zero);
+        opts.rollover = ToZero;
 
         let mut topts = HashMap::new();
         topts.insert("blksize".to_string(), "1024".to_string());
@@ -396,6 +2726,62 @@ mod test {
                                              Acknowledgment(2)]), Ok(()));
     }
 
+    #[test]
+    fn get_stats_report_negotiated_options() {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, _writer_rcv) = channel();
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 10;
+        opts.block_size = 1024;
+        let mut topts_ack = HashMap::new();
+        topts_ack.insert("blksize".to_string(), "256".to_string());
+        reader_snd.send((LOCALHOST, OptionAcknowledgment(topts_ack)));
+        reader_snd.send((LOCALHOST, Data(1, Vec::from_elem(9, 0u8))));
+        let mut writer = io::MemWriter::new();
+        let (res, stats) = get_internal(reader_rcv, None, None, writer_snd, LOCALHOST, Path::new("/path"), opts, &mut writer);
+        assert_eq!(Ok(()), res);
+        let negotiated = stats.options.unwrap();
+        assert_eq!(256, negotiated.block_size);
+        assert_eq!(10, negotiated.receive_timeout);
+    }
+
+    #[test]
+    fn get_proceeds_without_size_when_only_block_size_is_acknowledged() {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, writer_rcv) = channel();
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 10;
+        opts.block_size = 1024;
+        opts.transfer_size = Some(0);
+        let mut topts_ack = HashMap::new();
+        topts_ack.insert("blksize".to_string(), "1024".to_string());
+        assert_eq!(1024, TransferOptions::from_map(&topts_ack).block_size);
+        assert_eq!(None, TransferOptions::from_map(&topts_ack).transfer_size);
+        let data = gen_data(1024 + 10);
+        reader_snd.send((LOCALHOST, OptionAcknowledgment(topts_ack)));
+        reader_snd.send((LOCALHOST, Data(1, Vec::from_slice(data.slice_to(1024)))));
+        reader_snd.send((LOCALHOST, Data(2, Vec::from_slice(data.slice_from(1024)))));
+        let mut reported = Vec::new();
+        let mut writer = io::MemWriter::new();
+        let (res, stats) = get_internal_attempts(reader_rcv, None, writer_snd, LOCALHOST, Path::new("/path"),
+                                                 opts, 1, TransferHooks::new().progress(|transferred, total| {
+                                                     reported.push((transferred, total))
+                                                 }), &mut writer);
+        assert_eq!(Ok(()), res);
+        assert_eq!(data, writer.unwrap());
+        assert_eq!(vec![(1024, None), (1034, None)], reported);
+        let negotiated = stats.options.unwrap();
+        assert_eq!(1024, negotiated.block_size);
+        assert_eq!(None, negotiated.transfer_size);
+        let mut topts = HashMap::new();
+        topts.insert("blksize".to_string(), "1024".to_string());
+        topts.insert("tsize".to_string(), "0".to_string());
+        assert_eq!(vec![ReadRequest("/path".to_string(), Octet, topts),
+                        Acknowledgment(0),
+                        Acknowledgment(1),
+                        Acknowledgment(2)], receive_all(&writer_rcv));
+    }
+
     #[test]
     fn get_only_acknowledged_options_are_used() {
         let data = gen_data_sized(256, 256 + 9);
@@ -418,21 +2804,144 @@ mod test {
     }
 
     #[test]
-    fn get_options_are_only_accepted_when_they_are_first_received_packet() {
-        let data = gen_data(300);
+    fn get_rejects_oack_with_block_size_below_minimum() {
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 10;
+        opts.block_size = 1428;
+        opts.min_block_size = Some(1024);
+        let mut topts = HashMap::new();
+        topts.insert("blksize".to_string(), "1428".to_string());
+        let mut topts_ack = HashMap::new();
+        topts_ack.insert("blksize".to_string(), "512".to_string());
+        let res = get_assert_received_opts(opts, [],
+                                           [OptionAcknowledgment(topts_ack),
+                                            Data(1, Vec::from_elem(512, 0u8))],
+                                           [ReadRequest("/path".to_string(), Octet, topts),
+                                            Error(OptionNegotiationRejected,
+                                                  "Acknowledged block size is below the minimum".to_string())]);
+        assert_eq!(io::InvalidInput, res.unwrap_err().kind);
+    }
+
+    #[test]
+    fn get_rejects_oack_with_block_size_out_of_range() {
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 10;
+        opts.block_size = 1428;
+        let mut topts = HashMap::new();
+        topts.insert("blksize".to_string(), "1428".to_string());
+        let mut topts_ack = HashMap::new();
+        topts_ack.insert("blksize".to_string(), "100000".to_string());
+        let res = get_assert_received_opts(opts, [],
+                                           [OptionAcknowledgment(topts_ack)],
+                                           [ReadRequest("/path".to_string(), Octet, topts),
+                                            Error(OptionNegotiationRejected,
+                                                  "Acknowledged block size is out of range".to_string())]);
+        assert_eq!(io::InvalidInput, res.unwrap_err().kind);
+    }
+
+    #[test]
+    fn get_rejects_oack_changing_the_mode() {
+        let mut topts = HashMap::new();
+        topts.insert("mode".to_string(), "netascii".to_string());
+        let res = get_assert_received([],
+                                      [OptionAcknowledgment(topts),
+                                       Data(1, Vec::from_elem(10, 0u8))],
+                                      [ReadRequest("/path".to_string(), Octet, HashMap::new()),
+                                       Error(OptionNegotiationRejected,
+                                             "Acknowledged options conflict with the transfer mode".to_string())]);
+        assert_eq!(io::InvalidInput, res.unwrap_err().kind);
+    }
+
+    fn query_size_assert(oack: Option<&str>) -> Result<Option<u64>, TftpError> {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, writer_rcv) = channel();
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 10;
+        match oack {
+            Some(size) => {
+                let mut topts = HashMap::new();
+                topts.insert("tsize".to_string(), size.to_string());
+                reader_snd.send((LOCALHOST, OptionAcknowledgment(topts)));
+            }
+            None => reader_snd.send((LOCALHOST, Data(1, Vec::from_elem(10, 0u8))))
+        }
+        let res = query_size_internal(reader_rcv, None, writer_snd, LOCALHOST, Path::new("/path"), opts);
+        let mut topts = HashMap::new();
+        topts.insert("tsize".to_string(), "0".to_string());
+        assert_eq!(vec![ReadRequest("/path".to_string(), Octet, topts),
+                        Error(OptionNegotiationRejected, "Size query only".to_string())], receive_all(&writer_rcv));
+        res
+    }
+
+    #[test]
+    fn probe_options_returns_acknowledged_subset() {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, writer_rcv) = channel();
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 10;
+        opts.block_size = 1024;
+        opts.rollover = ToZero;
+        let mut topts = HashMap::new();
+        topts.insert("blksize".to_string(), "1024".to_string());
+        reader_snd.send((LOCALHOST, OptionAcknowledgment(topts.clone())));
+        let res = probe_options_internal(reader_rcv, None, writer_snd, LOCALHOST, Path::new("/path"), opts.clone(),
+                                         "Option probe only");
+        assert_eq!(Ok(topts), res);
+        assert_eq!(vec![ReadRequest("/path".to_string(), Octet, opts.to_options()),
+                        Error(OptionNegotiationRejected, "Option probe only".to_string())], receive_all(&writer_rcv));
+    }
+
+    #[test]
+    fn query_size_returns_size_from_oack() {
+        assert_eq!(Ok(Some(1234)), query_size_assert(Some("1234")));
+    }
+
+    #[test]
+    fn query_size_treats_echoed_zero_tsize_as_unknown() {
+        assert_eq!(Ok(None), query_size_assert(Some("0")));
+    }
+
+    #[test]
+    fn query_size_is_unknown_when_server_ignores_option() {
+        assert_eq!(Ok(None), query_size_assert(None));
+    }
+
+    #[test]
+    fn get_rejects_conflicting_second_oack() {
         let mut opts: TransferOptions = Default::default();
         opts.block_size = 400;
+        opts.receive_timeout = 10;
 
         let mut topts = HashMap::new();
         topts.insert("blksize".to_string(), "400".to_string());
 
         let mut topts2 = HashMap::new();
         topts2.insert("blksize".to_string(), "256".to_string());
+        let res = get_assert_received_opts(opts, [],
+                                           [OptionAcknowledgment(topts.clone()),
+                                            OptionAcknowledgment(topts2),
+                                            Data(1, Vec::from_elem(300, 0u8))],
+                                           [ReadRequest("/path".to_string(), Octet, topts),
+                                            Acknowledgment(0),
+                                            Error(IllegalOperation, "Conflicting option acknowledgment".to_string())]);
+        assert_eq!(io::InvalidInput, res.unwrap_err().kind);
+    }
+
+    #[test]
+    fn get_reacknowledges_retransmitted_oack() {
+        let data = gen_data(300);
+        let mut opts: TransferOptions = Default::default();
+        opts.block_size = 400;
+        opts.receive_timeout = 10;
+
+        let mut topts = HashMap::new();
+        topts.insert("blksize".to_string(), "400".to_string());
         assert_eq!(get_assert_received_opts(opts, data.as_slice(),
                                             [OptionAcknowledgment(topts.clone()),
-                                             OptionAcknowledgment(topts2),
+                                             OptionAcknowledgment(topts.clone()),
                                              Data(1, Vec::from_elem(300, 0u8))],
                                             [ReadRequest("/path".to_string(), Octet, topts),
+                                             Acknowledgment(0),
                                              Acknowledgment(0),
                                              Acknowledgment(1)]), Ok(()));
     }
@@ -444,13 +2953,85 @@ mod test {
         for packet in received.iter() {
             reader_snd.send((LOCALHOST, packet.clone()));
         }
-        let res = put_internal(reader_rcv, writer_snd, LOCALHOST, path, opts, reader);
+        let mut retry = FixedRetry::new(opts.resend_timeout);
+        let (res, _) = put_internal(reader_rcv, None, writer_snd, LOCALHOST, path, opts, &mut retry, TransferHooks::new(), reader);
         let sent = receive_all(&writer_rcv);
         println!("result = {}", res);
         assert_eq!(expected, sent.as_slice());
         res
     }
 
+    fn put_assert_resends(retry: &mut RetryStrategy, resends: uint) {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, writer_rcv) = channel();
+        let path = Path::new("/path");
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 1000;
+        let data = gen_data(DEFAULT_BLOCK_SIZE + 11);
+        let mut reader = io::BufReader::new(data.as_slice());
+        reader_snd.send((LOCALHOST, Acknowledgment(0)));
+
+        let mut expected = vec![WriteRequest("/path".to_string(), Octet, HashMap::new())];
+        for _ in range(0, resends + 1) {
+            expected.push(Data(1, Vec::from_elem(512, 0u8)));
+        }
+        expected.push(Error(Undefined, "Retry limit exceeded".to_string()));
+
+        let (res, _) = put_internal(reader_rcv, None, writer_snd, LOCALHOST, path, opts, retry, TransferHooks::new(), &mut reader);
+        let sent = receive_all(&writer_rcv);
+        assert_eq!(expected, sent);
+        assert_eq!(io::ConnectionAborted, res.unwrap_err().kind);
+    }
+
+    #[test]
+    fn put_gives_up_after_max_retries_option() {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, writer_rcv) = channel();
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 1000;
+        // A short timeout that is not requested from the peer
+        opts.resend_timeout = 2;
+        opts.request_options = Some(HashMap::new());
+        opts.max_retries = 3;
+        let data = gen_data(DEFAULT_BLOCK_SIZE + 11);
+        let mut reader = io::BufReader::new(data.as_slice());
+        reader_snd.send((LOCALHOST, Acknowledgment(0)));
+
+        let mut expected = vec![WriteRequest("/path".to_string(), Octet, HashMap::new())];
+        for _ in range(0u, 4) {
+            expected.push(Data(1, Vec::from_elem(512, 0u8)));
+        }
+        expected.push(Error(Undefined, "Retry limit exceeded".to_string()));
+        let mut retry = NegotiatedRetry;
+        let (res, stats) = put_internal(reader_rcv, None, writer_snd, LOCALHOST, Path::new("/path"), opts,
+                                        &mut retry, TransferHooks::new(), &mut reader);
+        assert_eq!(expected, receive_all(&writer_rcv));
+        let err = res.unwrap_err();
+        assert_eq!(io::ConnectionAborted, err.kind);
+        assert_eq!("Retry limit exceeded", err.desc);
+        assert_eq!(3, stats.summary.unwrap().resends);
+    }
+
+    #[test]
+    fn put_fixed_retry_gives_up_after_max_retries() {
+        let mut retry = FixedRetry { delay: 2, max_retries: Some(2) };
+        put_assert_resends(&mut retry, 2);
+    }
+
+    #[test]
+    fn put_exponential_backoff_gives_up_after_max_retries() {
+        let mut retry = ExponentialBackoff { initial: 1, max_delay: 4, max_retries: 3 };
+        put_assert_resends(&mut retry, 3);
+    }
+
+    #[test]
+    fn exponential_backoff_doubles_delay_up_to_max() {
+        let mut retry = ExponentialBackoff { initial: 100, max_delay: 500, max_retries: 4 };
+        let opts: TransferOptions = Default::default();
+        let delays: Vec<Option<u64>> = range(0u, 6).map(|a| retry.next_delay(a, &opts)).collect();
+        assert_eq!(vec![Some(100), Some(200), Some(400), Some(500), Some(500), None], delays);
+    }
+
     fn put_assert_sent_buf(reader: &mut Reader, received: &[Packet], expected: &[Packet]) -> IoResult<()> {
         let mut opts: TransferOptions = Default::default();
         opts.receive_timeout = 10;
@@ -462,6 +3043,32 @@ mod test {
         put_assert_sent_buf(&mut reader, received, expected)
     }
 
+    fn put_verified_assert(stored: &[u8]) -> Result<(), TftpError> {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, _writer_rcv) = channel();
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 10;
+        reader_snd.send((LOCALHOST, Acknowledgment(0)));
+        reader_snd.send((LOCALHOST, Acknowledgment(1)));
+        // Reply to the read request that downloads the file again
+        reader_snd.send((LOCALHOST, Data(1, Vec::from_slice(stored))));
+        let mut reader = io::BufReader::new(b"critical data");
+        put_verified_on_channels((reader_rcv, None, writer_snd), LOCALHOST, Path::new("/path"), opts, &mut reader)
+    }
+
+    #[test]
+    fn put_verified_succeeds_when_server_stored_the_upload() {
+        assert_eq!(Ok(()), put_verified_assert(b"critical data"));
+    }
+
+    #[test]
+    fn put_verified_fails_when_server_corrupted_the_upload() {
+        match put_verified_assert(b"critical dbta") {
+            Err(Io(err)) => assert_eq!("Downloaded file does not match the upload", err.desc),
+            res => fail!("unexpected result {}", res)
+        }
+    }
+
     #[test]
     fn put_sends_one_packet_sized_data() {
         let data = gen_data(111);
@@ -472,6 +3079,122 @@ mod test {
                                     Data(1, Vec::from_elem(111, 0u8))]), Ok(()));
     }
 
+    #[test]
+    fn put_sends_a_window_of_blocks_before_waiting_for_ack() {
+        let data = gen_data(DEFAULT_BLOCK_SIZE * 2 + 10);
+        let mut reader = io::BufReader::new(data.as_slice());
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 10;
+        opts.window_size = 2;
+        assert_eq!(put_assert_sent_opts(opts, &mut reader,
+                                        [OptionAcknowledgment(window_options("2")),
+                                         Acknowledgment(2),
+                                         Acknowledgment(3)],
+                                        [WriteRequest("/path".to_string(), Octet, window_options("2")),
+                                         Data(1, Vec::from_elem(512, 0u8)),
+                                         Data(2, Vec::from_elem(512, 1u8)),
+                                         Data(3, Vec::from_elem(10, 2u8))]), Ok(()));
+    }
+
+    #[test]
+    fn put_restarts_window_after_ack_from_its_middle() {
+        let data = gen_data(DEFAULT_BLOCK_SIZE * 3 + 10);
+        let mut reader = io::BufReader::new(data.as_slice());
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 10;
+        opts.window_size = 3;
+        assert_eq!(put_assert_sent_opts(opts, &mut reader,
+                                        [OptionAcknowledgment(window_options("3")),
+                                         Acknowledgment(1),
+                                         Acknowledgment(4)],
+                                        [WriteRequest("/path".to_string(), Octet, window_options("3")),
+                                         Data(1, Vec::from_elem(512, 0u8)),
+                                         Data(2, Vec::from_elem(512, 1u8)),
+                                         Data(3, Vec::from_elem(512, 2u8)),
+                                         Data(2, Vec::from_elem(512, 1u8)),
+                                         Data(3, Vec::from_elem(512, 2u8)),
+                                         Data(4, Vec::from_elem(10, 3u8))]), Ok(()));
+    }
+
+    #[test]
+    fn put_encodes_netascii_line_break_split_between_blocks() {
+        let mut data = Vec::from_elem(511, b'a');
+        data.push_all(b"\nb");
+        let mut reader = io::BufReader::new(data.as_slice());
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 10;
+        opts.mode = NetAscii;
+        let mut first = Vec::from_elem(511, b'a');
+        first.push(b'\r');
+        assert_eq!(put_assert_sent_opts(opts, &mut reader,
+                                        [Acknowledgment(0),
+                                         Acknowledgment(1),
+                                         Acknowledgment(2)],
+                                        [WriteRequest("/path".to_string(), NetAscii, HashMap::new()),
+                                         Data(1, first),
+                                         Data(2, Vec::from_slice(b"\nb"))]), Ok(()));
+    }
+
+    #[test]
+    fn put_from_iter_rechunks_to_block_size() {
+        let data = gen_data(DEFAULT_BLOCK_SIZE * 2 + 10);
+        let chunks: Vec<Vec<u8>> = [3u, 700, 1, 0, 315, 15].iter().scan(0u, |start, &len| {
+            let chunk = Vec::from_slice(data.slice(*start, *start + len));
+            *start += len;
+            Some(chunk)
+        }).collect();
+        let mut reader = IterReader::new(chunks.move_iter());
+        assert_eq!(put_assert_sent_buf(&mut reader,
+                                       [Acknowledgment(0),
+                                        Acknowledgment(1),
+                                        Acknowledgment(2),
+                                        Acknowledgment(3)],
+                                       [WriteRequest("/path".to_string(), Octet, HashMap::new()),
+                                        Data(1, Vec::from_elem(512, 0u8)),
+                                        Data(2, Vec::from_elem(512, 1u8)),
+                                        Data(3, Vec::from_elem(10, 2u8))]), Ok(()));
+    }
+
+    struct LoggingReader<'a> {
+        inner: io::BufReader<'a>,
+        events: Sender<&'static str>
+    }
+
+    impl<'a> Reader for LoggingReader<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+            self.events.send("read");
+            self.inner.read(buf)
+        }
+    }
+
+    #[test]
+    fn put_reads_next_block_before_ack_with_read_ahead() {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, writer_rcv) = channel();
+        let (events_snd, events_rcv) = channel();
+        let data = gen_data(DEFAULT_BLOCK_SIZE * 2 + 10);
+        let mut reader = LoggingReader { inner: io::BufReader::new(data.as_slice()), events: events_snd.clone() };
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 10;
+        opts.read_ahead = true;
+        for i in range(0u16, 4) {
+            reader_snd.send((LOCALHOST, Acknowledgment(i)));
+        }
+        let mut retry = FixedRetry::new(opts.resend_timeout);
+        let (res, _) = put_internal(reader_rcv, None, writer_snd, LOCALHOST, Path::new("/path"), opts,
+                                    &mut retry, TransferHooks::new().progress(|_, _| events_snd.send("ack")),
+                                    &mut reader);
+        assert_eq!(Ok(()), res);
+        assert_eq!(vec![WriteRequest("/path".to_string(), Octet, HashMap::new()),
+                        Data(1, Vec::from_elem(512, 0u8)),
+                        Data(2, Vec::from_elem(512, 1u8)),
+                        Data(3, Vec::from_elem(10, 2u8))], receive_all(&writer_rcv));
+        drop(reader);
+        drop(events_snd);
+        let events: Vec<&'static str> = events_rcv.iter().collect();
+        assert_eq!(vec!["read", "read", "ack", "read", "ack", "ack"], events);
+    }
+
     #[test]
     fn put_sends_one_packet_data_of_max_packet_size() {
         let data = gen_data(DEFAULT_BLOCK_SIZE);
@@ -484,6 +3207,24 @@ mod test {
                                     Data(2, Vec::from_elem(0, 1u8))]), Ok(()));
     }
 
+    #[test]
+    fn put_sends_empty_terminator_after_exact_block_of_non_default_size() {
+        let data = gen_data_sized(256, 256);
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 10;
+        opts.block_size = 256;
+        let mut topts = HashMap::new();
+        topts.insert("blksize".to_string(), "256".to_string());
+        let mut reader = io::BufReader::new(data.as_slice());
+        assert_eq!(put_assert_sent_opts(opts, &mut reader,
+                                        [OptionAcknowledgment(topts.clone()),
+                                         Acknowledgment(1),
+                                         Acknowledgment(2)],
+                                        [WriteRequest("/path".to_string(), Octet, topts),
+                                         Data(1, Vec::from_elem(256, 0u8)),
+                                         Data(2, Vec::new())]), Ok(()));
+    }
+
     #[test]
     fn put_sends_multi_packet_sized_data() {
         let data = gen_data(DEFAULT_BLOCK_SIZE + 200);
@@ -499,7 +3240,7 @@ mod test {
     #[test]
     fn put_timeouts_if_not_receiving_packets() {
         let res = put_assert_sent([], [], [WriteRequest("/path".to_string(), Octet, HashMap::new())]);
-        assert_eq!(Err(ERR_TIMEOUT.clone()), res);
+        assert_eq!(Err(ERR_NO_RESPONSE.clone()), res);
     }
 
     #[test]
@@ -523,8 +3264,195 @@ mod test {
         let res = put_assert_sent_opts(opts, &mut reader, [OptionAcknowledgment(topt.clone())],
                                        [WriteRequest("/path".to_string(), Octet, topt),
                                         Data(1, Vec::from_elem(512, 0u8)),
-                                        Data(1, Vec::from_elem(512, 0u8))]);
-        assert_eq!(Err(ERR_TIMEOUT.clone()), res);
+                                        Data(1, Vec::from_elem(512, 0u8)),
+                                        Error(Undefined, "Transfer stalled".to_string())]);
+        assert_eq!(Err(ERR_STALLED.clone()), res);
+    }
+
+    #[test]
+    fn put_resends_after_acknowledged_timeout() {
+        let mut opts: TransferOptions = Default::default();
+        // Nothing would be resent before the transfer stalls with the requested timeout
+        opts.resend_timeout = 10000;
+        opts.receive_timeout = 100;
+        let mut topts = HashMap::new();
+        topts.insert("timeout".to_string(), "5".to_string());
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, writer_rcv) = channel();
+        reader_snd.send((LOCALHOST, OptionAcknowledgment(topts)));
+        let data = gen_data(100);
+        let mut reader = io::BufReader::new(data.as_slice());
+        let mut retry = NegotiatedRetry;
+        let (res, _) = put_internal(reader_rcv, None, writer_snd, LOCALHOST, Path::new("/path"),
+                                    opts, &mut retry, TransferHooks::new(), &mut reader);
+        assert!(res.is_err());
+        let sent = receive_all(&writer_rcv);
+        let data_sent = sent.iter().filter(|packet| match **packet { Data(1, _) => true, _ => false }).count();
+        assert!(data_sent > 1);
+    }
+
+    #[test]
+    fn put_succeeds_when_final_ack_arrives_after_resend() {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, writer_rcv) = channel();
+        let (sent_snd, sent_rcv) = channel();
+        spawn(proc() {
+            let mut sent = Vec::new();
+            for (_, packet) in writer_rcv.iter() {
+                match packet {
+                    WriteRequest(..) => reader_snd.send((LOCALHOST, Acknowledgment(0))),
+                    // The acknowledgment of the first copy of the final block is lost
+                    Data(1, _) if sent.len() > 1 => reader_snd.send((LOCALHOST, Acknowledgment(1))),
+                    _ => {}
+                }
+                sent.push(packet);
+            }
+            sent_snd.send(sent);
+        });
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 1000;
+        let data = gen_data(100);
+        let mut reader = io::BufReader::new(data.as_slice());
+        let mut retry = FixedRetry::new(5);
+        let (res, _) = put_internal(reader_rcv, None, writer_snd, LOCALHOST, Path::new("/path"),
+                                    opts, &mut retry, TransferHooks::new(), &mut reader);
+        assert_eq!(Ok(()), res);
+        assert_eq!(vec![WriteRequest("/path".to_string(), Octet, HashMap::new()),
+                        Data(1, Vec::from_elem(100, 0u8)),
+                        Data(1, Vec::from_elem(100, 0u8))], sent_rcv.recv());
+    }
+
+    #[test]
+    fn put_uses_transfer_mode_for_request_and_sent_data() {
+        let mut peer = bind_socket(EPHEMERAL).unwrap();
+        let peer_addr = peer.socket_name().unwrap();
+        let (seen_snd, seen_rcv) = channel();
+        spawn(proc() {
+            let mut peer = peer;
+            let mut buf = [0u8, ..516];
+            let (addr, request) = receive_packet(&mut peer, Octet, buf).unwrap();
+            send_packet(&mut peer, &addr, Octet, &Acknowledgment(0)).unwrap();
+            let (len, _) = peer.recvfrom(buf).unwrap();
+            send_packet(&mut peer, &addr, Octet, &Acknowledgment(1)).unwrap();
+            seen_snd.send((request, Vec::from_slice(buf.slice_to(len))));
+        });
+        let mut opts: TransferOptions = Default::default();
+        opts.mode = NetAscii;
+        let mut reader = io::BufReader::new(b"a\nb");
+        assert_eq!(Ok(()), put(peer_addr, Path::new("/path"), opts, &mut reader));
+        let (request, data) = seen_rcv.recv();
+        assert_eq!(WriteRequest("/path".to_string(), NetAscii, HashMap::new()), request);
+        assert_eq!(vec![0u8, 3, 0, 1, b'a', b'\r', b'\n', b'b'], data);
+    }
+
+    #[test]
+    fn put_compresses_payload_only_when_compression_is_acknowledged() {
+        let data = gen_data(DEFAULT_BLOCK_SIZE * 20);
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 10;
+        opts.compress = true;
+        let mut topts = HashMap::new();
+        topts.insert("compress".to_string(), "gzip".to_string());
+
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, writer_rcv) = channel();
+        reader_snd.send((LOCALHOST, OptionAcknowledgment(topts)));
+        for i in range(1u16, 30) {
+            reader_snd.send((LOCALHOST, Acknowledgment(i)));
+        }
+        let mut reader = io::BufReader::new(data.as_slice());
+        let mut retry = FixedRetry::new(opts.resend_timeout);
+        let (res, _) = put_internal(reader_rcv, None, writer_snd, LOCALHOST, Path::new("/path"),
+                                    opts.clone(), &mut retry, TransferHooks::new(), &mut reader);
+        assert_eq!(Ok(()), res);
+        let mut payload = Vec::new();
+        for packet in receive_all(&writer_rcv).iter() {
+            match *packet {
+                Data(_, ref block) => payload.push_all(block.as_slice()),
+                _ => {}
+            }
+        }
+        assert!(payload.len() < data.len());
+        assert_eq!(data, gzip::decompress(payload.as_slice()).unwrap());
+
+        let mut reader = io::BufReader::new(data.as_slice());
+        let mut expected = vec![WriteRequest("/path".to_string(), Octet, opts.to_options())];
+        for i in range(0u, 21) {
+            let len = if i < 20 { DEFAULT_BLOCK_SIZE } else { 0 };
+            expected.push(Data(i as u16 + 1, Vec::from_elem(len, i as u8)));
+        }
+        let mut received = vec![Acknowledgment(0)];
+        received.extend(range(1u16, 22).map(|i| Acknowledgment(i)));
+        assert_eq!(put_assert_sent_opts(opts, &mut reader, received.as_slice(), expected.as_slice()), Ok(()));
+    }
+
+    #[test]
+    fn put_counts_uncompressed_bytes_of_compressed_upload() {
+        let data = gen_data(gzip::MEMBER_SIZE * 2 + 100);
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 10;
+        opts.compress = true;
+        opts.transfer_size = Some(data.len() as u64);
+        let mut topts = HashMap::new();
+        topts.insert("compress".to_string(), "gzip".to_string());
+        topts.insert("tsize".to_string(), data.len().to_str());
+
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, writer_rcv) = channel();
+        reader_snd.send((LOCALHOST, OptionAcknowledgment(topts)));
+        for i in range(1u16, 200) {
+            reader_snd.send((LOCALHOST, Acknowledgment(i)));
+        }
+        let mut reader = io::BufReader::new(data.as_slice());
+        let mut retry = FixedRetry::new(opts.resend_timeout);
+        let mut progress = Vec::new();
+        let (res, _) = put_internal(reader_rcv, None, writer_snd, LOCALHOST, Path::new("/path"),
+                                    opts, &mut retry, TransferHooks::new().progress(|sent, _| progress.push(sent)),
+                                    &mut reader);
+        assert_eq!(Ok(()), res);
+        assert_eq!(Some(&(data.len() as u64)), progress.last());
+        let mut payload = Vec::new();
+        for packet in receive_all(&writer_rcv).iter() {
+            match *packet {
+                Data(_, ref block) => payload.push_all(block.as_slice()),
+                _ => {}
+            }
+        }
+        assert_eq!(data, gzip::decompress(payload.as_slice()).unwrap());
+    }
+
+    #[test]
+    fn put_compresses_netascii_encoded_payload() {
+        let data = gen_text(DEFAULT_BLOCK_SIZE * 20);
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 10;
+        opts.mode = NetAscii;
+        opts.compress = true;
+        let mut topts = HashMap::new();
+        topts.insert("compress".to_string(), "gzip".to_string());
+
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, writer_rcv) = channel();
+        reader_snd.send((LOCALHOST, OptionAcknowledgment(topts)));
+        for i in range(1u16, 30) {
+            reader_snd.send((LOCALHOST, Acknowledgment(i)));
+        }
+        let mut reader = io::BufReader::new(data.as_slice());
+        let mut retry = FixedRetry::new(opts.resend_timeout);
+        let (res, _) = put_internal(reader_rcv, None, writer_snd, LOCALHOST, Path::new("/path"),
+                                    opts, &mut retry, TransferHooks::new(), &mut reader);
+        assert_eq!(Ok(()), res);
+        let mut payload = Vec::new();
+        for packet in receive_all(&writer_rcv).iter() {
+            match *packet {
+                Data(_, ref block) => payload.push_all(block.as_slice()),
+                _ => {}
+            }
+        }
+        let mut decoder = NetasciiDecoder::new(Lf);
+        let decoded = decoder.decode(gzip::decompress(payload.as_slice()).unwrap().as_slice()).unwrap();
+        assert_eq!(Ok(()), decoder.finish());
+        assert_eq!(data, decoded);
     }
 
     #[test]
@@ -533,7 +3461,7 @@ mod test {
         assert_eq!(put_assert_sent(data.as_slice(),
                                    [Data(1, Vec::new()),
                                     Acknowledgment(0),
-                                    Acknowledgment(2),
+                                    Acknowledgment(0),
                                     Acknowledgment(1),
                                     Acknowledgment(2)],
                                    [WriteRequest("/path".to_string(), Octet, HashMap::new()),
@@ -541,6 +3469,204 @@ mod test {
                                     Data(2, Vec::from_elem(10, 1u8))]), Ok(()));
     }
 
+    #[test]
+    fn put_rejects_acknowledgment_of_unsent_block() {
+        let data = gen_data(DEFAULT_BLOCK_SIZE + 10);
+        let res = put_assert_sent(data.as_slice(),
+                                  [Acknowledgment(0),
+                                   Acknowledgment(5)],
+                                  [WriteRequest("/path".to_string(), Octet, HashMap::new()),
+                                   Data(1, Vec::from_elem(512, 0u8)),
+                                   Error(IllegalOperation, "Acknowledgment of an unsent block".to_string())]);
+        assert_eq!(io::InvalidInput, res.unwrap_err().kind);
+    }
+
+    #[test]
+    fn put_sends_abort_error_configured_number_of_times() {
+        let data = gen_data(DEFAULT_BLOCK_SIZE + 10);
+        let mut reader = io::BufReader::new(data.as_slice());
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 10;
+        opts.error_resend_count = 3;
+        let error = Error(IllegalOperation, "Acknowledgment of an unsent block".to_string());
+        let res = put_assert_sent_opts(opts, &mut reader,
+                                       [Acknowledgment(0),
+                                        Acknowledgment(5)],
+                                       [WriteRequest("/path".to_string(), Octet, HashMap::new()),
+                                        Data(1, Vec::from_elem(512, 0u8)),
+                                        error.clone(),
+                                        error.clone(),
+                                        error]);
+        assert_eq!(io::InvalidInput, res.unwrap_err().kind);
+    }
+
+    #[test]
+    fn put_netascii_with_transfer_size_of_the_unencoded_data() {
+        let data = b"line\nline\n";
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 10;
+        opts.mode = NetAscii;
+        opts.transfer_size = Some(data.len() as u64);
+
+        let mut topts = HashMap::new();
+        topts.insert("tsize".to_string(), data.len().to_string());
+        let mut reader = io::BufReader::new(data);
+        assert_eq!(put_assert_sent_opts(opts, &mut reader,
+                                        [OptionAcknowledgment(topts.clone()),
+                                         Acknowledgment(1)],
+                                        [WriteRequest("/path".to_string(), NetAscii, topts),
+                                         Data(1, Vec::from_slice(b"line\r\nline\r\n"))]), Ok(()));
+    }
+
+    #[test]
+    fn put_reports_reader_shorter_than_transfer_size() {
+        let data = gen_data(500);
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 10;
+        opts.transfer_size = Some(1000);
+
+        let mut topts = HashMap::new();
+        topts.insert("tsize".to_string(), "1000".to_string());
+        let mut reader = io::BufReader::new(data.as_slice());
+        let res = put_assert_sent_opts(opts, &mut reader,
+                                       [OptionAcknowledgment(topts.clone()),
+                                        Acknowledgment(1)],
+                                       [WriteRequest("/path".to_string(), Octet, topts),
+                                        Data(1, Vec::from_elem(500, 0u8))]);
+        assert_eq!(io::OtherIoError, res.unwrap_err().kind);
+    }
+
+    struct StreamingReader {
+        remaining: uint,
+        max_read: uint
+    }
+
+    impl Reader for StreamingReader {
+        fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+            if self.remaining == 0 {
+                return Err(io::standard_error(io::EndOfFile))
+            }
+            self.max_read = ::std::cmp::max(self.max_read, buf.len());
+            let len = ::std::cmp::min(self.remaining, buf.len());
+            self.remaining -= len;
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn put_reads_at_most_one_block_at_a_time() {
+        let blocks = 200u;
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, writer_rcv) = channel();
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 10;
+        let mut reader = StreamingReader { remaining: blocks * DEFAULT_BLOCK_SIZE, max_read: 0 };
+        for i in range(0u16, blocks as u16 + 2) {
+            reader_snd.send((LOCALHOST, Acknowledgment(i)));
+        }
+
+        let mut retry = FixedRetry::new(opts.resend_timeout);
+        let (res, stats) = put_internal(reader_rcv, None, writer_snd, LOCALHOST, Path::new("/path"),
+                                        opts, &mut retry, TransferHooks::new(), &mut reader);
+        assert_eq!(Ok(()), res);
+        assert_eq!((blocks * DEFAULT_BLOCK_SIZE) as u64, stats.transferred);
+        assert_eq!(DEFAULT_BLOCK_SIZE, reader.max_read);
+        let sent = receive_all(&writer_rcv);
+        assert_eq!(blocks + 2, sent.len());
+        for packet in sent.iter() {
+            match *packet {
+                Data(_, ref data) => assert!(data.len() <= DEFAULT_BLOCK_SIZE),
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn put_block_size_can_change_between_blocks() {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, writer_rcv) = channel();
+        let path = Path::new("/path");
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 10;
+        let data = gen_data(DEFAULT_BLOCK_SIZE + 300);
+        let mut reader = io::BufReader::new(data.as_slice());
+        for i in range(0u16, 4) {
+            reader_snd.send((LOCALHOST, Acknowledgment(i)));
+        }
+
+        let mut retry = FixedRetry::new(opts.resend_timeout);
+        let hooks = TransferHooks::new().block_hook(|id, size| {
+            if id == 2 {
+                *size = 256;
+            }
+        });
+        let (res, _) = put_internal(reader_rcv, None, writer_snd, LOCALHOST, path, opts, &mut retry, hooks, &mut reader);
+        let sent = receive_all(&writer_rcv);
+        assert_eq!(vec![WriteRequest("/path".to_string(), Octet, HashMap::new()),
+                        Data(1, Vec::from_elem(512, 0u8)),
+                        Data(2, Vec::from_elem(256, 1u8)),
+                        Data(3, Vec::from_elem(44, 1u8))], sent);
+        assert_eq!(Ok(()), res);
+    }
+
+    #[test]
+    fn put_block_size_can_grow_with_read_ahead() {
+        let (reader_snd, reader_rcv) = channel();
+        let (writer_snd, writer_rcv) = channel();
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 10;
+        opts.read_ahead = true;
+        let data = gen_data(256 + DEFAULT_BLOCK_SIZE + 10);
+        let mut reader = io::BufReader::new(data.as_slice());
+        for i in range(0u16, 4) {
+            reader_snd.send((LOCALHOST, Acknowledgment(i)));
+        }
+
+        let mut retry = FixedRetry::new(opts.resend_timeout);
+        let hooks = TransferHooks::new().block_hook(|id, size| {
+            *size = if id == 1 { 256 } else { DEFAULT_BLOCK_SIZE };
+        });
+        let (res, _) = put_internal(reader_rcv, None, writer_snd, LOCALHOST, Path::new("/path"), opts,
+                                    &mut retry, hooks, &mut reader);
+        assert_eq!(Ok(()), res);
+        assert_eq!(vec![WriteRequest("/path".to_string(), Octet, HashMap::new()),
+                        Data(1, Vec::from_slice(data.slice(0, 256))),
+                        Data(2, Vec::from_slice(data.slice(256, 768))),
+                        Data(3, Vec::from_slice(data.slice_from(768)))], receive_all(&writer_rcv));
+    }
+
+    #[test]
+    fn put_truncates_block_at_max_upload_bytes() {
+        let data = gen_data(DEFAULT_BLOCK_SIZE * 3);
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 10;
+        opts.max_upload_bytes = Some(DEFAULT_BLOCK_SIZE as u64 + 100);
+        let mut reader = io::BufReader::new(data.as_slice());
+        assert_eq!(put_assert_sent_opts(opts, &mut reader,
+                                        [Acknowledgment(0),
+                                         Acknowledgment(1),
+                                         Acknowledgment(2)],
+                                        [WriteRequest("/path".to_string(), Octet, HashMap::new()),
+                                         Data(1, Vec::from_elem(512, 0u8)),
+                                         Data(2, Vec::from_elem(100, 1u8))]), Ok(()));
+    }
+
+    #[test]
+    fn put_sends_empty_terminator_when_max_upload_bytes_is_on_block_boundary() {
+        let data = gen_data(DEFAULT_BLOCK_SIZE * 3);
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 10;
+        opts.max_upload_bytes = Some(DEFAULT_BLOCK_SIZE as u64);
+        let mut reader = io::BufReader::new(data.as_slice());
+        assert_eq!(put_assert_sent_opts(opts, &mut reader,
+                                        [Acknowledgment(0),
+                                         Acknowledgment(1),
+                                         Acknowledgment(2)],
+                                        [WriteRequest("/path".to_string(), Octet, HashMap::new()),
+                                         Data(1, Vec::from_elem(512, 0u8)),
+                                         Data(2, Vec::new())]), Ok(()));
+    }
+
     #[test]
     fn put_does_rollover_to_zero() {
         let (reader_snd, reader_rcv) = channel();
@@ -569,7 +3695,8 @@ mod test {
         expected.push(Data(0, Vec::from_slice([0u8])));
         expected.push(Data(1, Vec::new()));
 
-        let res = put_internal(reader_rcv, writer_snd, LOCALHOST, path, opts, &mut reader);
+        let mut retry = FixedRetry::new(opts.resend_timeout);
+        let (res, _) = put_internal(reader_rcv, None, writer_snd, LOCALHOST, path, opts, &mut retry, TransferHooks::new(), &mut reader);
         println!("result = {}", res);
         let sent = receive_all(&writer_rcv);
         for (e, s) in expected.iter().zip(sent.iter()) {
@@ -587,7 +3714,7 @@ mod test {
         static MAX: uint = ::std::u16::MAX as uint;
         let mut opts: TransferOptions = Default::default();
         opts.block_size = 1;
-        opts.rollover = Some(One);
+        opts.rollover = ToOne;
         let data = Vec::from_elem(MAX + 1, 0u8);
         let mut reader = io::BufReader::new(data.as_slice());
         let mut topt = HashMap::new();
@@ -608,7 +3735,8 @@ mod test {
         expected.push(Data(1, Vec::from_slice([0u8])));
         expected.push(Data(2, Vec::new()));
 
-        let res = put_internal(reader_rcv, writer_snd, LOCALHOST, path, opts, &mut reader);
+        let mut retry = FixedRetry::new(opts.resend_timeout);
+        let (res, _) = put_internal(reader_rcv, None, writer_snd, LOCALHOST, path, opts, &mut retry, TransferHooks::new(), &mut reader);
         println!("result = {}", res);
         let sent = receive_all(&writer_rcv);
         for (e, s) in expected.iter().zip(sent.iter()) {
@@ -625,7 +3753,7 @@ mod test {
         opts.transfer_size = Some(0);
         opts.receive_timeout = 20;
         opts.resend_timeout = 11;
-        opts.rollover = Some(Zero);
+        opts.rollover = ToZero;
 
         let mut topts = HashMap::new();
         topts.insert("blksize".to_string(), "1024".to_string());
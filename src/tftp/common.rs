@@ -1,18 +1,26 @@
 use std::io;
+use std::u16;
+use std::cmp::min;
 use std::u64;
 use std::io::{IoResult, IoError};
 use std::io::Timer;
-use std::io::net::ip::SocketAddr;
-use std::comm::Select;
+use std::io::timer;
+use std::io::net::ip::{SocketAddr, IpAddr, Ipv4Addr};
+use std::comm::{Select, Empty, Disconnected};
 use std::hash::Hash;
 use std::from_str;
 use std::default::Default;
+use std::ascii::StrAsciiExt;
 
 use std::collections::hashmap::HashMap;
 
-use protocol::DEFAULT_BLOCK_SIZE;
-use protocol::{Mode, RolloverMethod, Options, Octet};
-use protocol::{Packet, Error, UnknownTransferId};
+use time;
+
+use protocol::{DEFAULT_BLOCK_SIZE, MIN_BLOCK_SIZE, MAX_BLOCK_SIZE};
+use protocol::{Mode, RolloverPolicy, Unset, Options, Octet, Newline};
+use protocol::{Packet, BlockId, Error, Undefined, UnknownTransferId, OptionNegotiationRejected, IllegalOperation};
+use protocol::{AccessViolation, DiskFull};
+use protocol::{Opcode, DATA, ACK, ERROR, OACK};
 
 #[deriving(Show, Clone)]
 pub struct TransferOptions {
@@ -21,7 +29,63 @@ pub struct TransferOptions {
     pub transfer_size: Option<u64>,
     pub receive_timeout: u64,
     pub resend_timeout: u64,
-    pub rollover: Option<RolloverMethod>
+    pub transfer_timeout: Option<u64>,
+    pub max_upload_bytes: Option<u64>,
+    /// `put_file` only, send the size of the local file as `tsize` when
+    /// `transfer_size` is not set
+    pub auto_transfer_size: bool,
+    /// Get only, abort a download that goes on for more blocks, so a peer can
+    /// not keep sending data forever when the size is not known up front
+    pub max_blocks: Option<u64>,
+    /// Blocks sent before waiting for an acknowledgment, requested with the
+    /// `windowsize` option (RFC 7440) and 1 unless the peer acknowledges it
+    pub window_size: u16,
+    pub record: bool,
+    pub rollover: RolloverPolicy,
+    /// Milliseconds to keep answering retransmissions of the final packet after
+    /// the transfer has completed, 0 to return immediately
+    pub dally_timeout: u64,
+    /// Abort with an error reply when the peer sends a packet with an unknown
+    /// opcode, instead of ignoring it
+    pub strict: bool,
+    /// Leave a block unacknowledged when the writer would block, so it is
+    /// written again when the peer retransmits it, instead of failing
+    pub retry_would_block: bool,
+    /// Local address the transfer socket is bound to, on a random ephemeral port
+    pub local_addr: IpAddr,
+    /// Client only, advanced. Send from one socket and receive on another, both
+    /// bound to `local_addr`. The server sees the sending socket as our TID and
+    /// replies to it, so replies only reach the receiving socket when something
+    /// in between, such as a NAT, forwards them there
+    pub split_sockets: bool,
+    /// Sent verbatim in the request instead of the options derived from the
+    /// other fields
+    pub request_options: Option<Options>,
+    /// Consecutive resends of the same packet after which the transfer is
+    /// aborted, enforced by the default `NegotiatedRetry` strategy
+    pub max_retries: uint,
+    /// Times an Error packet aborting the transfer is sent. Errors are not
+    /// acknowledged, so sending it more than once makes it likelier to arrive
+    pub error_resend_count: uint,
+    /// Blocks between the progress lines logged at info level, 0 to disable
+    pub progress_log_interval: u64,
+    /// Line ending that netascii line breaks are written as when receiving
+    pub newline: Newline,
+    /// Log the summary of the finished transfer at info level, it is returned
+    /// in `TransferStats::summary` either way
+    pub log_summary: bool,
+    /// Put only, read the block following the window while waiting for its
+    /// acknowledgment, so it can be sent as soon as the acknowledgment arrives
+    pub read_ahead: bool,
+    /// Smallest block size to accept from the peer's option acknowledgment
+    pub min_block_size: Option<u16>,
+    /// Request `compress=gzip`, when acknowledged the payload is gzip
+    /// compressed. Uploads are compressed as they are read, downloads are
+    /// buffered in memory and decompressed once complete
+    pub compress: bool,
+    /// Request `crc=crc16`, when acknowledged every data block ends with a
+    /// CRC-16 of its payload and corrupted blocks are left for retransmission
+    pub crc: bool
 }
 
 fn find_as<K: Hash + Eq, T: from_str::FromStr>(h: &HashMap<K, String>, key: K) -> Option<T> {
@@ -29,13 +93,52 @@ fn find_as<K: Hash + Eq, T: from_str::FromStr>(h: &HashMap<K, String>, key: K) -
 }
 
 impl TransferOptions {
+    /// Default options with the largest block size that fits in a single
+    /// datagram on a link with the given MTU.
+    pub fn for_mtu(mtu: u16) -> TransferOptions {
+        // IP header, UDP header and TFTP data header
+        let overhead = 20 + 8 + 4;
+        let mut opts: TransferOptions = Default::default();
+        opts.block_size = if (mtu as uint) < overhead + MIN_BLOCK_SIZE {
+            MIN_BLOCK_SIZE
+        } else {
+            min(mtu as uint - overhead, MAX_BLOCK_SIZE)
+        };
+        opts
+    }
+
+    pub fn has_non_default_options(&self) -> bool {
+        let defaults: TransferOptions = Default::default();
+        self.block_size != defaults.block_size
+            || self.resend_timeout != defaults.resend_timeout
+            || self.transfer_size != defaults.transfer_size
+            || self.rollover != defaults.rollover
+            || self.window_size != defaults.window_size
+            || self.compress != defaults.compress
+            || self.crc != defaults.crc
+    }
+
     pub fn to_options(&self) -> Options {
+        match self.request_options {
+            Some(ref opts) => return opts.clone(),
+            None => {}
+        }
         let mut h = HashMap::new();
+        if !self.has_non_default_options() {
+            return h
+        }
         let defaults: TransferOptions = Default::default();
         self.insert_to(&mut h, "blksize".to_string(), &defaults, |o| o.block_size);
         self.insert_to(&mut h, "timeout".to_string(), &defaults, |o| o.resend_timeout);
         self.insert_to_opt(&mut h, "tsize".to_string(), &defaults, |o| o.transfer_size);
-        self.insert_to_opt(&mut h, "rollover".to_string(), &defaults, |o| o.rollover);
+        self.insert_to(&mut h, "rollover".to_string(), &defaults, |o| o.rollover);
+        self.insert_to(&mut h, "windowsize".to_string(), &defaults, |o| o.window_size);
+        if self.compress {
+            h.insert("compress".to_string(), "gzip".to_string());
+        }
+        if self.crc {
+            h.insert("crc".to_string(), "crc16".to_string());
+        }
         h
     }
 
@@ -56,7 +159,10 @@ impl TransferOptions {
         for key in opts.keys() {
             match key.as_slice() {
                 "blksize" => {
-                    default.block_size = find_as(opts, "blksize".to_string()).unwrap_or(default.block_size);
+                    match find_as::<String, uint>(opts, "blksize".to_string()) {
+                        Some(size) if size >= MIN_BLOCK_SIZE && size <= MAX_BLOCK_SIZE => default.block_size = size,
+                        _ => warn!("Ignoring invalid block size {}", opts.get(key))
+                    }
                 }
                 "tsize" => {
                     default.transfer_size = find_as(opts, "tsize".to_string());
@@ -65,13 +171,172 @@ impl TransferOptions {
                     default.resend_timeout = find_as(opts, "timeout".to_string()).unwrap_or(default.resend_timeout);
                 }
                 "rollover" => {
-                    default.rollover = find_as(opts, "rollover".to_string());
+                    default.rollover = find_as(opts, "rollover".to_string()).unwrap_or(Unset);
+                }
+                "windowsize" => {
+                    default.window_size = find_as(opts, "windowsize".to_string()).unwrap_or(default.window_size);
+                }
+                "compress" => {
+                    default.compress = opts.get(key).as_slice().eq_ignore_ascii_case("gzip");
+                }
+                "crc" => {
+                    default.crc = opts.get(key).as_slice().eq_ignore_ascii_case("crc16");
                 }
                 _ => continue
             }
         }
         default
     }
+
+    /// Checks that the options are usable for a transfer, the error describes
+    /// the first violated invariant.
+    pub fn validate(&self) -> IoResult<()> {
+        if self.block_size < MIN_BLOCK_SIZE || self.block_size > MAX_BLOCK_SIZE {
+            return invalid_options("Block size is out of range",
+                                   format!("{} is not within {}-{}", self.block_size, MIN_BLOCK_SIZE, MAX_BLOCK_SIZE))
+        }
+        if self.receive_timeout == 0 {
+            return invalid_options("Receive timeout must be positive", "receive_timeout is 0".to_string())
+        }
+        if self.resend_timeout == 0 {
+            return invalid_options("Resend timeout must be positive", "resend_timeout is 0".to_string())
+        }
+        if self.receive_timeout < self.resend_timeout {
+            // Not an error, but the transfer is aborted before anything is resent
+            warn!("Receive timeout of {}ms is shorter than the resend timeout of {}ms",
+                  self.receive_timeout, self.resend_timeout);
+        }
+        if self.transfer_timeout == Some(0) {
+            return invalid_options("Transfer timeout must be positive", "transfer_timeout is 0".to_string())
+        }
+        if self.window_size == 0 {
+            return invalid_options("Window size must be positive", "window_size is 0".to_string())
+        }
+        if self.error_resend_count == 0 {
+            return invalid_options("Error resend count must be positive", "error_resend_count is 0".to_string())
+        }
+        // The transfer size is not checked against the block size and rollover
+        // policy. Block ids wrap to the first block of the policy, to 0 when it
+        // is unset, so every size fits and no combination conflicts.
+        Ok(())
+    }
+
+    /// Data is translated for the requested mode from the start of the
+    /// transfer, so acknowledged options can not switch to a different one.
+    pub fn check_mode(&self, opts: &Options) -> IoResult<()> {
+        match opts.find(&"mode".to_string()) {
+            Some(value) if from_str::<Mode>(value.as_slice()) != Some(self.mode) => {
+                Err(IoError {
+                    kind: io::InvalidInput,
+                    desc: "Acknowledged options conflict with the transfer mode",
+                    detail: Some(format!("requested {}, acknowledged {}", self.mode, value))
+                })
+            }
+            _ => Ok(())
+        }
+    }
+
+    /// Fails if the block size in `opts` is not a valid `blksize` value, which
+    /// `from_map` would otherwise replace with the default.
+    pub fn check_block_size(&self, opts: &Options) -> IoResult<()> {
+        match opts.find(&"blksize".to_string()) {
+            Some(value) => match from_str::<uint>(value.as_slice()) {
+                Some(size) if size >= MIN_BLOCK_SIZE && size <= MAX_BLOCK_SIZE => Ok(()),
+                _ => Err(IoError {
+                    kind: io::InvalidInput,
+                    desc: "Acknowledged block size is out of range",
+                    detail: Some(format!("{} is not within {}-{}", value, MIN_BLOCK_SIZE, MAX_BLOCK_SIZE))
+                })
+            },
+            None => Ok(())
+        }
+    }
+
+    pub fn check_min_block_size(&self, opts: &Options) -> IoResult<()> {
+        let block_size = TransferOptions::from_map(opts).block_size;
+        match self.min_block_size {
+            Some(min) if block_size < min as uint => {
+                Err(IoError {
+                    kind: io::InvalidInput,
+                    desc: "Acknowledged block size is below the minimum",
+                    detail: Some(format!("acknowledged {}, minimum {}", block_size, min))
+                })
+            }
+            _ => Ok(())
+        }
+    }
+
+    /// Options from user supplied `key=value` pairs. Unlike `from_map` unknown
+    /// keys and unparsable values are errors and the result is validated.
+    pub fn from_kv(pairs: &[(&str, &str)]) -> IoResult<TransferOptions> {
+        let mut opts: TransferOptions = Default::default();
+        for &(key, value) in pairs.iter() {
+            let parsed = match key {
+                "blksize" => from_str::<uint>(value).map(|v| opts.block_size = v),
+                "timeout" => from_str::<u64>(value).map(|v| opts.resend_timeout = v),
+                "tsize" => from_str::<u64>(value).map(|v| opts.transfer_size = Some(v)),
+                "rollover" => from_str::<RolloverPolicy>(value).map(|v| opts.rollover = v),
+                "mode" => from_str::<Mode>(value).map(|v| opts.mode = v),
+                "window" => from_str::<u16>(value).map(|v| opts.window_size = v),
+                _ => {
+                    return Err(IoError {
+                        kind: io::InvalidInput,
+                        desc: "Unknown option",
+                        detail: Some(key.to_string())
+                    })
+                }
+            };
+            if parsed.is_none() {
+                return Err(IoError {
+                    kind: io::InvalidInput,
+                    desc: "Invalid option value",
+                    detail: Some(format!("{}={}", key, value))
+                })
+            }
+        }
+        try!(opts.validate());
+        Ok(opts)
+    }
+
+    /// Options acknowledged by the peer combined with the settings that never go
+    /// on the wire, which are kept from `self`.
+    pub fn negotiate(&self, opts: &Options) -> TransferOptions {
+        let mut negotiated = TransferOptions::from_map(opts);
+        negotiated.mode = self.mode;
+        negotiated.receive_timeout = self.receive_timeout;
+        negotiated.transfer_timeout = self.transfer_timeout;
+        negotiated.max_upload_bytes = self.max_upload_bytes;
+        negotiated.max_blocks = self.max_blocks;
+        negotiated.auto_transfer_size = self.auto_transfer_size;
+        negotiated.record = self.record;
+        negotiated.dally_timeout = self.dally_timeout;
+        negotiated.strict = self.strict;
+        negotiated.retry_would_block = self.retry_would_block;
+        negotiated.local_addr = self.local_addr;
+        negotiated.split_sockets = self.split_sockets;
+        negotiated.request_options = self.request_options.clone();
+        negotiated.max_retries = self.max_retries;
+        negotiated.error_resend_count = self.error_resend_count;
+        negotiated.read_ahead = self.read_ahead;
+        negotiated.progress_log_interval = self.progress_log_interval;
+        negotiated.newline = self.newline.clone();
+        negotiated.log_summary = self.log_summary;
+        negotiated.min_block_size = self.min_block_size;
+        // Only use compression that was asked for
+        negotiated.compress = negotiated.compress && self.compress;
+        negotiated.crc = negotiated.crc && self.crc;
+        // The peer may only shrink the window
+        negotiated.window_size = min(negotiated.window_size, self.window_size);
+        negotiated
+    }
+}
+
+fn invalid_options(desc: &'static str, detail: String) -> IoResult<()> {
+    Err(IoError {
+        kind: io::InvalidInput,
+        desc: desc,
+        detail: Some(detail)
+    })
 }
 
 impl Default for TransferOptions {
@@ -82,27 +347,552 @@ impl Default for TransferOptions {
             transfer_size: None,
             receive_timeout: 5000,
             resend_timeout: 1000,
-            rollover: None
+            transfer_timeout: None,
+            max_upload_bytes: None,
+            auto_transfer_size: false,
+            max_blocks: None,
+            window_size: 1,
+            record: false,
+            rollover: Unset,
+            dally_timeout: 0,
+            strict: false,
+            retry_would_block: false,
+            local_addr: Ipv4Addr(0, 0, 0, 0),
+            split_sockets: false,
+            request_options: None,
+            max_retries: 5,
+            error_resend_count: 1,
+            read_ahead: false,
+            progress_log_interval: 0,
+            newline: Newline::native(),
+            log_summary: true,
+            min_block_size: None,
+            compress: false,
+            crc: false
+        }
+    }
+}
+
+/// Chainable setters for the options that go on the wire, starting from the
+/// defaults. `build` validates the result like `TransferOptions::validate`.
+#[deriving(Show, Clone)]
+pub struct TransferOptionsBuilder {
+    opts: TransferOptions
+}
+
+impl TransferOptionsBuilder {
+    pub fn new() -> TransferOptionsBuilder {
+        TransferOptionsBuilder {
+            opts: Default::default()
+        }
+    }
+
+    pub fn block_size(mut self, block_size: uint) -> TransferOptionsBuilder {
+        self.opts.block_size = block_size;
+        self
+    }
+
+    /// Resend timeout in milliseconds, requested with the `timeout` option.
+    pub fn timeout(mut self, timeout: u64) -> TransferOptionsBuilder {
+        self.opts.resend_timeout = timeout;
+        self
+    }
+
+    pub fn transfer_size(mut self, transfer_size: u64) -> TransferOptionsBuilder {
+        self.opts.transfer_size = Some(transfer_size);
+        self
+    }
+
+    pub fn rollover(mut self, rollover: RolloverPolicy) -> TransferOptionsBuilder {
+        self.opts.rollover = rollover;
+        self
+    }
+
+    pub fn mode(mut self, mode: Mode) -> TransferOptionsBuilder {
+        self.opts.mode = mode;
+        self
+    }
+
+    pub fn build(self) -> IoResult<TransferOptions> {
+        try!(self.opts.validate());
+        Ok(self.opts)
+    }
+}
+
+pub trait RetryStrategy {
+    /// Delay in milliseconds before resending for the given attempt (0 is the
+    /// initial send), or `None` to give up. `opts` are the options of the
+    /// transfer at that point, including the ones acknowledged by the peer.
+    fn next_delay(&mut self, attempt: uint, opts: &TransferOptions) -> Option<u64>;
+}
+
+/// Resends after the `resend_timeout` of the transfer, which follows the
+/// `timeout` acknowledged by the peer, at most `max_retries` times.
+pub struct NegotiatedRetry;
+
+impl RetryStrategy for NegotiatedRetry {
+    fn next_delay(&mut self, attempt: uint, opts: &TransferOptions) -> Option<u64> {
+        if attempt > opts.max_retries {
+            None
+        } else {
+            Some(opts.resend_timeout)
+        }
+    }
+}
+
+pub struct FixedRetry {
+    pub delay: u64,
+    pub max_retries: Option<uint>
+}
+
+impl FixedRetry {
+    pub fn new(delay: u64) -> FixedRetry {
+        FixedRetry {
+            delay: delay,
+            max_retries: None
+        }
+    }
+}
+
+impl RetryStrategy for FixedRetry {
+    fn next_delay(&mut self, attempt: uint, _opts: &TransferOptions) -> Option<u64> {
+        match self.max_retries {
+            Some(max) if attempt > max => None,
+            _ => Some(self.delay)
+        }
+    }
+}
+
+pub struct ExponentialBackoff {
+    pub initial: u64,
+    pub max_delay: u64,
+    pub max_retries: uint
+}
+
+impl RetryStrategy for ExponentialBackoff {
+    fn next_delay(&mut self, attempt: uint, _opts: &TransferOptions) -> Option<u64> {
+        if attempt > self.max_retries {
+            return None
+        }
+        let mut delay = self.initial;
+        for _ in range(0, attempt) {
+            delay = if delay >= self.max_delay / 2 { self.max_delay } else { delay * 2 };
+        }
+        Some(delay)
+    }
+}
+
+#[deriving(Show, Eq, PartialEq, Clone)]
+pub enum Direction {
+    Sent,
+    Received
+}
+
+/// A packet exchanged with the peer, with a timestamp in nanoseconds.
+pub type Record = (u64, Direction, Packet);
+
+pub struct TransferStats {
+    pub transferred: u64,
+    pub recording: Option<Vec<Record>>,
+    /// Smoothed round-trip time in nanoseconds, if any reply could be timed
+    pub rtt: Option<u64>,
+    /// Options as acknowledged by the peer, including the ones not understood
+    /// here and therefore not in effect
+    pub acknowledged_options: Option<Options>,
+    /// Set once the transfer has finished
+    pub summary: Option<TransferSummary>,
+    /// Options in effect when the transfer ended, after negotiation with the
+    /// peer
+    pub options: Option<TransferOptions>,
+    /// Error code and message the peer aborted the transfer with
+    pub peer_error: Option<(Error, String)>,
+    /// Set when the transfer failed because the peer stopped responding
+    pub timeout: Option<TimeoutCause>
+}
+
+impl TransferStats {
+    pub fn new() -> TransferStats {
+        TransferStats {
+            transferred: 0,
+            recording: None,
+            rtt: None,
+            acknowledged_options: None,
+            summary: None,
+            options: None,
+            peer_error: None,
+            timeout: None
+        }
+    }
+}
+
+/// The outcome of a finished transfer, as logged by `log_summary`.
+#[deriving(Show, PartialEq, Clone)]
+pub struct TransferSummary {
+    pub filename: String,
+    pub mode: Mode,
+    /// Negotiated block size
+    pub block_size: uint,
+    pub transferred: u64,
+    /// Nanoseconds from sending the request until the transfer finished
+    pub elapsed: u64,
+    pub resends: uint,
+    pub status: IoResult<()>
+}
+
+/// The progress line that is due after `blocks` blocks, one is logged every
+/// `progress_log_interval` blocks.
+pub fn progress_log(opts: &TransferOptions, blocks: u64, transferred: u64) -> Option<String> {
+    if opts.progress_log_interval == 0 || blocks % opts.progress_log_interval != 0 {
+        return None
+    }
+    let line = match opts.transfer_size {
+        Some(size) if size > 0 => {
+            format!("transferred {} of {} bytes ({}%)", transferred, size, transferred * 100 / size)
         }
+        _ => format!("transferred {} bytes", transferred)
+    };
+    Some(line)
+}
+
+/// Logs `summary` as a single line at info level.
+pub fn log_summary(summary: &TransferSummary) {
+    let status = match summary.status {
+        Ok(()) => "ok".to_string(),
+        Err(ref err) => err.to_str()
+    };
+    info!("{} mode={} blksize={} bytes={} elapsed={}ms resends={} status={}",
+          summary.filename, summary.mode, summary.block_size, summary.transferred,
+          summary.elapsed / 1000000, summary.resends, status);
+}
+
+/// Source of the timestamps round trips are measured with, in nanoseconds.
+pub trait Clock {
+    fn now(&mut self) -> u64;
+}
+
+/// `time::precise_time_ns`, the clock transfers use.
+pub struct PreciseClock;
+
+impl Clock for PreciseClock {
+    fn now(&mut self) -> u64 {
+        time::precise_time_ns()
     }
 }
 
+/// Smoothed round-trip time estimate, updated like TCP's SRTT. Replies to
+/// resent packets are not sampled as it is unknown which copy they answer.
+pub struct RttEstimator {
+    pub srtt: Option<u64>,
+    sent_at: Option<u64>,
+    resent: bool,
+    clock: Box<Clock + Send>
+}
+
+impl RttEstimator {
+    pub fn new() -> RttEstimator {
+        RttEstimator::with_clock(box PreciseClock)
+    }
+
+    pub fn with_clock(clock: Box<Clock + Send>) -> RttEstimator {
+        RttEstimator {
+            srtt: None,
+            sent_at: None,
+            resent: false,
+            clock: clock
+        }
+    }
+
+    /// Forgets the estimate, the clock is kept.
+    pub fn reset(&mut self) {
+        self.srtt = None;
+        self.sent_at = None;
+        self.resent = false;
+    }
+
+    pub fn on_send(&mut self) {
+        let now = self.clock.now();
+        if self.sent_at.is_some() {
+            self.resent = true;
+        } else {
+            self.sent_at = Some(now);
+        }
+    }
+
+    pub fn on_receive(&mut self) {
+        let now = self.clock.now();
+        match self.sent_at.take() {
+            Some(sent_at) if !self.resent && now >= sent_at => {
+                let sample = now - sent_at;
+                self.srtt = Some(match self.srtt {
+                    Some(srtt) => srtt - srtt / 8 + sample / 8,
+                    None => sample
+                });
+            }
+            _ => {}
+        }
+        self.resent = false;
+    }
+}
+
+/// Milliseconds between the copies of an Error packet sent by `abort`.
+pub static ERROR_RESEND_GAP: u64 = 5;
+
+/// Receivers of the packets and unknown opcodes read from a socket, and the
+/// sender of the packets written to it.
+pub type Channels = (Receiver<(SocketAddr, Packet)>, Option<Receiver<(SocketAddr, u16)>>, Sender<(SocketAddr, Packet)>);
+
 pub struct LoopData<T, D> {
     pub remote_addr: SocketAddr,
     pub reader_port: Receiver<(SocketAddr, Packet)>,
+    /// Datagrams with an unknown opcode, only reported in strict mode
+    pub unknown_port: Option<Receiver<(SocketAddr, u16)>>,
     pub writer_chan: Sender<(SocketAddr, Packet)>,
+    /// Pause and resume requests from the caller
+    pub control_port: Option<Receiver<Control>>,
+    pub paused: bool,
+    pub last_sent: Option<Packet>,
     pub opts: TransferOptions,
+    /// Options acknowledged by the peer, `None` until an OACK is accepted
+    pub acknowledged_options: Option<Options>,
     pub current_id: u16,
     pub resend: bool,
+    pub transferred: u64,
+    pub blocks: u64,
+    pub unacked: uint,
+    pub recording: Option<Vec<Record>>,
+    pub rtt: RttEstimator,
+    /// Resends so far
+    pub resends: uint,
+    /// Error code and message the peer aborted the transfer with
+    pub peer_error: Option<(Error, String)>,
+    /// Why the peer was given up on, once it stopped responding
+    pub timeout: Option<TimeoutCause>,
     pub path_handle: T,
     pub data: D
 }
 
+impl<T, D> LoopData<T, D> {
+    pub fn send(&mut self, packet: Packet) {
+        self.rtt.on_send();
+        let addr = self.remote_addr;
+        self.send_to(addr, packet)
+    }
+
+    pub fn send_to(&mut self, addr: SocketAddr, packet: Packet) {
+        self.record(Sent, &packet);
+        if addr == self.remote_addr {
+            self.last_sent = Some(packet.clone());
+        }
+        self.writer_chan.send((addr, packet));
+    }
+
+    /// Sends an Error packet to the peer `error_resend_count` times, a few
+    /// milliseconds apart.
+    pub fn abort(&mut self, code: Error, msg: String) {
+        for i in range(0, self.opts.error_resend_count) {
+            if i > 0 {
+                timer::sleep(ERROR_RESEND_GAP);
+            }
+            self.send(Error(code, msg.clone()));
+        }
+    }
+
+    fn record(&mut self, direction: Direction, packet: &Packet) {
+        match self.recording {
+            Some(ref mut recording) => recording.push((time::precise_time_ns(), direction, packet.clone())),
+            None => {}
+        }
+    }
+
+    /// Uses the options acknowledged by the peer, or rejects them with an
+    /// error reply when they can not be honoured.
+    pub fn accept_options(&mut self, opts: &Options) -> IoResult<()> {
+        let checked = self.opts.check_mode(opts)
+            .and_then(|_| self.opts.check_block_size(opts))
+            .and_then(|_| self.opts.check_min_block_size(opts));
+        match checked {
+            Ok(()) => {
+                self.opts = self.opts.negotiate(opts);
+                self.acknowledged_options = Some(opts.clone());
+                Ok(())
+            }
+            Err(err) => {
+                self.abort(OptionNegotiationRejected, err.desc.to_string());
+                Err(err)
+            }
+        }
+    }
+
+    /// Back to the state before the request was sent, so the transfer can be
+    /// started over on the same channels. The transfer specific `data` is left
+    /// to the caller.
+    pub fn reset(&mut self, remote_addr: SocketAddr, opts: TransferOptions, current_id: BlockId, resend: bool) {
+        self.remote_addr = remote_addr;
+        self.paused = false;
+        self.last_sent = None;
+        self.opts = opts;
+        self.acknowledged_options = None;
+        self.current_id = current_id;
+        self.resend = resend;
+        self.transferred = 0;
+        self.blocks = 0;
+        self.unacked = 0;
+        self.rtt.reset();
+        self.resends = 0;
+        self.peer_error = None;
+        self.timeout = None;
+    }
+
+    pub fn stats(self) -> TransferStats {
+        self.into_stats_and_channels().val0()
+    }
+
+    /// Logs the progress after a block was transferred, when it is due.
+    pub fn log_progress(&self) {
+        match progress_log(&self.opts, self.blocks, self.transferred) {
+            Some(line) => info!("{}", line),
+            None => {}
+        }
+    }
+
+    /// The stats of the transfer and the socket channels, which another
+    /// transfer may go on to use.
+    pub fn into_stats_and_channels(self) -> (TransferStats, Channels) {
+        let LoopData {
+            reader_port, unknown_port, writer_chan, opts, acknowledged_options,
+            transferred, recording, rtt, peer_error, timeout, ..
+        } = self;
+        let stats = TransferStats {
+            transferred: transferred,
+            recording: recording,
+            rtt: rtt.srtt,
+            acknowledged_options: acknowledged_options,
+            summary: None,
+            options: Some(opts),
+            peer_error: peer_error,
+            timeout: timeout
+        };
+        (stats, (reader_port, unknown_port, writer_chan))
+    }
+
+    /// The stats of a finished transfer with its summary, which is also logged
+    /// unless `log_summary` is unset.
+    pub fn finish(self, filename: String, res: IoResult<()>, started: u64) -> (IoResult<()>, TransferStats) {
+        let (res, stats, _) = self.finish_with_channels(filename, res, started);
+        (res, stats)
+    }
+
+    /// Like `finish`, but also returns the socket channels.
+    pub fn finish_with_channels(self,
+                                filename: String,
+                                res: IoResult<()>,
+                                started: u64) -> (IoResult<()>, TransferStats, Channels) {
+        let summary = TransferSummary {
+            filename: filename,
+            mode: self.opts.mode,
+            block_size: self.opts.block_size,
+            transferred: self.transferred,
+            elapsed: time::precise_time_ns() - started,
+            resends: self.resends,
+            status: res.clone()
+        };
+        if self.opts.log_summary {
+            log_summary(&summary);
+        }
+        let (mut stats, channels) = self.into_stats_and_channels();
+        stats.summary = Some(summary);
+        (res, stats, channels)
+    }
+}
+
+pub fn next_block_id(opts: &TransferOptions, id: BlockId) -> BlockId {
+    if id == u16::MAX {
+        opts.rollover.first_block()
+    } else {
+        id + 1
+    }
+}
+
+/// The block id before `id`, the inverse of `next_block_id`.
+pub fn prev_block_id(opts: &TransferOptions, id: BlockId) -> BlockId {
+    if id == opts.rollover.first_block() {
+        u16::MAX
+    } else {
+        id - 1
+    }
+}
+
+/// Whether `id` comes after `current`, taking block id wraparound into account.
+pub fn is_future_block(current: BlockId, id: BlockId) -> bool {
+    let ahead = id - current;
+    ahead != 0 && ahead < 0x8000
+}
+
+#[deriving(Show, Eq, PartialEq, Clone)]
+pub enum TimeoutCause {
+    /// Nothing was received from the peer, the request was probably lost
+    NoResponse,
+    /// The peer replied but stopped responding during the transfer
+    TransferStalled,
+    /// The peer did not respond to any of the resends
+    RetriesExhausted,
+    /// The transfer did not finish within its `transfer_timeout`
+    DeadlineExceeded
+}
+
+impl TimeoutCause {
+    fn desc(&self) -> &'static str {
+        match *self {
+            NoResponse => "No response from peer",
+            TransferStalled => "Transfer stalled",
+            RetriesExhausted => "Retry limit exceeded",
+            DeadlineExceeded => "Transfer deadline exceeded"
+        }
+    }
+}
+
+/// Why a transfer failed, so that callers can tell the error codes sent by the
+/// peer apart without matching on strings.
+#[deriving(Show, PartialEq, Clone)]
+pub enum TftpError {
+    /// The peer aborted the transfer with an Error packet
+    Protocol(Error, String),
+    /// The peer stopped responding
+    Timeout(TimeoutCause),
+    Io(IoError)
+}
+
+impl TftpError {
+    /// Classifies the error a transfer ended with, `stats` tells whether the
+    /// peer sent an Error packet or stopped responding.
+    pub fn from_transfer(err: IoError, stats: &TransferStats) -> TftpError {
+        match (&stats.peer_error, &stats.timeout) {
+            (&Some((code, ref msg)), _) => Protocol(code, msg.clone()),
+            (&None, &Some(ref cause)) => Timeout(cause.clone()),
+            (&None, &None) => Io(err)
+        }
+    }
+}
+
+/// Sent on a transfer's control channel. A paused transfer sends no new
+/// packets but still answers retransmissions of the last packet it handled.
+#[deriving(Show, Eq, PartialEq, Clone)]
+pub enum Control {
+    Pause,
+    Resume
+}
+
+pub static GET_REPLIES: &'static [Opcode] = &[OACK, DATA];
+pub static PUT_REPLIES: &'static [Opcode] = &[OACK, ACK];
+
 #[deriving(Eq, PartialEq, Show)]
 enum Selected {
-    Timeout,
+    Deadline,
+    ReceiveTimeout,
     ResendTimeout,
-    ReceivePacket
+    ReceivePacket,
+    ReceiveUnknown,
+    ReceiveControl
 }
 
 pub enum LoopControl<T> {
@@ -112,8 +902,6 @@ pub enum LoopControl<T> {
     Return(T)
 }
 
-pub struct Void;
-
 macro_rules! control( ($e:expr) => {
     match $e {
         Normal => {},
@@ -123,86 +911,626 @@ macro_rules! control( ($e:expr) => {
     }
 })
 
-pub fn receive_loop<T, D>(mut d: LoopData<T, D>,
+/// The error code that tells the peer why the received data could not be
+/// stored.
+pub fn local_error_code(err: &IoError) -> Error {
+    match err.kind {
+        io::PermissionDenied => AccessViolation,
+        _ if is_disk_full(err) => DiskFull,
+        _ => Undefined
+    }
+}
+
+/// Whether writing failed for lack of space, which a writer reports by
+/// accepting none of the data. Pipes and sockets may accept part of it, and an
+/// out of space file system is an `OtherIoError` without its OS error, so
+/// neither is told apart from other failures.
+pub fn is_disk_full(err: &IoError) -> bool {
+    err.kind == io::ShortWrite(0)
+}
+
+// Tells the peer the transfer is over, so it does not have to time out as well.
+// Until its first reply the peer's TID is unknown and nothing is sent.
+fn abort_on_timeout<T, D>(d: &mut LoopData<T, D>, first: bool, err: IoError) -> IoResult<()> {
+    if !first {
+        d.abort(Undefined, err.desc.to_string());
+    }
+    Err(err)
+}
+
+pub fn receive_loop<T, D>(d: &mut LoopData<T, D>,
                           resend: bool,
-                          init: |&LoopData<T, D>|,
+                          retry: &mut RetryStrategy,
+                          first_replies: &[Opcode],
+                          init: |&mut LoopData<T, D>|,
                           loop_start: |&mut LoopData<T, D>| -> LoopControl<IoResult<()>>,
                           handle_packet: |&mut LoopData<T, D>, bool, &Packet, &mut bool| -> LoopControl<IoResult<()>>) -> IoResult<()> {
 
     let mut timer = try!(Timer::new());
     let mut resend_timer = try!(Timer::new());
+    let mut deadline_timer = try!(Timer::new());
     let mut first = true;
 
+    let mut deadline = deadline_timer.oneshot(d.opts.transfer_timeout.unwrap_or(u64::MAX));
+
     let mut timeout = timer.oneshot(d.opts.receive_timeout);
     let mut reset_timeout = false;
+    let mut attempt = 0u;
+    // Whether the next send is a resend after a timeout
+    let mut resending = false;
+    let mut last_received: Option<Packet> = None;
 
-    init(&d);
+    init(&mut *d);
     loop {
-        let mut resend_timeout = if resend {
-            resend_timer.oneshot(d.opts.resend_timeout)
-        } else {
-            resend_timer.oneshot(u64::MAX)
-        };
-        control!(loop_start(&mut d));
         if reset_timeout {
             timeout = timer.oneshot(d.opts.receive_timeout);
             reset_timeout = false;
+            attempt = 0;
+        }
+        let mut resend_timeout = if resend && !d.paused {
+            match retry.next_delay(attempt, &d.opts) {
+                Some(delay) => {
+                    if resending {
+                        d.resends += 1;
+                        resending = false;
+                    }
+                    resend_timer.oneshot(delay)
+                }
+                None => {
+                    info!("Giving up after {} attempts", attempt);
+                    d.timeout = Some(RetriesExhausted);
+                    return abort_on_timeout(d, first, IoError {
+                        kind: io::ConnectionAborted,
+                        desc: RetriesExhausted.desc(),
+                        detail: Some(format!("no progress after {} resend timeouts", attempt))
+                    })
+                }
+            }
+        } else {
+            resend_timer.oneshot(u64::MAX)
+        };
+        if !d.paused {
+            control!(loop_start(&mut *d));
         }
         let selected = {
             let select = Select::new();
+            let mut deadline_handle = select.handle(&mut deadline);
             let mut timeout_handle = select.handle(&mut timeout);
             let mut resend_timeout_handle = select.handle(&mut resend_timeout);
+            // Added before the reader so a pause takes effect before any packet queued after it
+            let mut control_handle = d.control_port.as_mut().map(|port| select.handle(port));
             let mut reader_handle = select.handle(&mut d.reader_port);
+            let mut unknown_handle = d.unknown_port.as_mut().map(|port| select.handle(port));
             unsafe {
+                deadline_handle.add();
                 timeout_handle.add();
                 resend_timeout_handle.add();
+                for handle in control_handle.mut_iter() {
+                    handle.add();
+                }
                 reader_handle.add();
+                for handle in unknown_handle.mut_iter() {
+                    handle.add();
+                }
             }
             let select_id = select.wait();
-            if select_id == timeout_handle.id() {
+            if select_id == deadline_handle.id() {
+                info!("Transfer deadline exceeded");
+                Deadline
+            } else if select_id == timeout_handle.id() {
                 info!("Connection timeout");
-                Timeout
+                ReceiveTimeout
             } else if select_id == resend_timeout_handle.id() {
                 info!("Resend timeout");
                 d.resend = true;
                 ResendTimeout
-            } else {
+            } else if select_id == reader_handle.id() {
                 ReceivePacket
+            } else if control_handle.as_ref().map_or(false, |h| h.id() == select_id) {
+                ReceiveControl
+            } else {
+                ReceiveUnknown
             }
         };
-        if selected == Timeout {
-            return Err(IoError {
+        if selected == Deadline {
+            d.timeout = Some(DeadlineExceeded);
+            return abort_on_timeout(d, first, IoError {
+                kind: io::TimedOut,
+                desc: DeadlineExceeded.desc(),
+                detail: None
+            })
+        } else if selected == ReceiveTimeout {
+            let cause = if first { NoResponse } else { TransferStalled };
+            let desc = cause.desc();
+            d.timeout = Some(cause);
+            return abort_on_timeout(d, first, IoError {
                 kind: io::ConnectionAborted,
-                desc: "Connection timeout",
+                desc: desc,
                 detail: None
             })
         } else if selected == ResendTimeout {
+            attempt += 1;
+            resending = true;
             continue
+        } else if selected == ReceiveControl {
+            match recv_ready(d.control_port.as_ref().unwrap()) {
+                Ok(None) => {}
+                Ok(Some(Pause)) => {
+                    info!("Transfer paused");
+                    d.paused = true;
+                }
+                Ok(Some(Resume)) => {
+                    info!("Transfer resumed");
+                    d.paused = false;
+                    reset_timeout = true;
+                }
+                Err(()) => d.control_port = None
+            }
+            continue
+        } else if selected == ReceiveUnknown {
+            let (addr, opcode) = match recv_ready(d.unknown_port.as_ref().unwrap()) {
+                Ok(Some(received)) => received,
+                Ok(None) => continue,
+                Err(()) => {
+                    d.unknown_port = None;
+                    continue
+                }
+            };
+            if first || addr != d.remote_addr {
+                warn!("[{}] Ignoring packet with unknown opcode {}", addr.to_str(), opcode);
+                continue
+            }
+            d.abort(IllegalOperation, "Unknown opcode".to_string());
+            return Err(IoError {
+                kind: io::InvalidInput,
+                desc: "Received packet with unknown opcode",
+                detail: Some(format!("opcode {}", opcode))
+            })
         }
-        let (addr, packet) = d.reader_port.recv();
+        let (addr, packet) = match recv_ready(&d.reader_port) {
+            Ok(Some(received)) => received,
+            Ok(None) => continue,
+            Err(()) => {
+                return Err(IoError {
+                    kind: io::ConnectionAborted,
+                    desc: "Socket reader terminated",
+                    detail: None
+                })
+            }
+        };
+        d.record(Received, &packet);
         if addr != d.remote_addr && !first {
             warn!("Different TID: {}, {}", addr.to_str(), d.remote_addr.to_str());
             let err_packet = Error(UnknownTransferId, "Unknown TID".to_string());
-            d.writer_chan.send((addr, err_packet))
+            d.send_to(addr, err_packet)
         } else {
             let first_packet = first;
             if first {
-                if addr.ip == d.remote_addr.ip {
+                // Only a reply to our request may establish the peer's TID. The
+                // server answers from a fresh port, so any port on its address
+                // counts; an Error from there is surfaced below.
+                let opcode = packet.opcode();
+                let reply = opcode == ERROR || first_replies.contains(&opcode);
+                if addr.ip == d.remote_addr.ip && reply {
                     first = false;
                     d.remote_addr = addr;
                 } else {
+                    warn!("[{}] Ignoring unexpected first packet: {}", addr.to_str(), packet.to_str());
                     continue
                 }
             }
             match packet {
-                err@Error(..) => return Err(err.to_ioerror().unwrap()),
+                Error(code, ref msg) => {
+                    d.peer_error = Some((code, msg.clone()));
+                    return Err(packet.to_ioerror().unwrap())
+                }
                 _ => {}
             }
-            if first_packet && !packet.is_option_ack() {
-                d.opts = Default::default();
+            if d.paused {
+                // Keep the peer waiting by answering its retransmissions only
+                if last_received.as_ref() == Some(&packet) {
+                    match d.last_sent.clone() {
+                        Some(last_sent) => d.send(last_sent),
+                        None => {}
+                    }
+                }
+                reset_timeout = true;
+                continue
+            }
+            last_received = Some(packet.clone());
+            d.rtt.on_receive();
+            // Options the server side acknowledged itself stay in effect
+            if first_packet && !packet.is_option_ack() && d.acknowledged_options.is_none() {
+                d.opts = d.opts.negotiate(&HashMap::new());
             }
-            control!(handle_packet(&mut d, first_packet, &packet, &mut reset_timeout));
+            control!(handle_packet(&mut *d, first_packet, &packet, &mut reset_timeout));
         }
     }
     Ok(())
 }
+
+/// Takes the value `Select` reported as ready without blocking. `Ok(None)` if
+/// the port turned out to be empty after all, `Err(())` if it is disconnected.
+fn recv_ready<T: Send>(port: &Receiver<T>) -> Result<Option<T>, ()> {
+    match port.try_recv() {
+        Ok(value) => Ok(Some(value)),
+        Err(Empty) => {
+            debug!("Selected port has nothing to receive");
+            Ok(None)
+        }
+        Err(Disconnected) => Err(())
+    }
+}
+
+/// Answers retransmissions of the final packet until `timeout` milliseconds
+/// pass, in case our reply to it was lost. `reply` returns the answer to a
+/// received packet, or `None` to ignore it.
+pub fn dally<T, D>(d: &mut LoopData<T, D>, timeout: u64, reply: |&Packet| -> Option<Packet>) -> IoResult<()> {
+    let mut timer = try!(Timer::new());
+    let mut timeout = timer.oneshot(timeout);
+    loop {
+        let received = {
+            let select = Select::new();
+            let mut timeout_handle = select.handle(&mut timeout);
+            let mut reader_handle = select.handle(&mut d.reader_port);
+            unsafe {
+                timeout_handle.add();
+                reader_handle.add();
+            }
+            select.wait() == reader_handle.id()
+        };
+        if !received {
+            return Ok(())
+        }
+        let (addr, packet) = match recv_ready(&d.reader_port) {
+            Ok(Some(received)) => received,
+            Ok(None) => continue,
+            // The transfer is already complete, there is nothing left to answer
+            Err(()) => return Ok(())
+        };
+        d.record(Received, &packet);
+        if addr == d.remote_addr {
+            match reply(&packet) {
+                Some(answer) => d.send(answer),
+                None => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::default::Default;
+    use std::collections::hashmap::HashMap;
+
+    use super::{TransferOptions, TransferOptionsBuilder, RttEstimator, Clock, is_future_block, recv_ready};
+    use super::{next_block_id, prev_block_id};
+    use super::{RetryStrategy, NegotiatedRetry};
+    use super::progress_log;
+    use protocol::{Unset, ToZero, ToOne, NetAscii, DEFAULT_BLOCK_SIZE};
+
+    #[test]
+    fn recv_ready_does_not_block_on_empty_port() {
+        let (snd, rcv) = channel();
+        assert_eq!(Ok(None), recv_ready(&rcv));
+        snd.send(1u);
+        assert_eq!(Ok(Some(1u)), recv_ready(&rcv));
+        drop(snd);
+        assert_eq!(Err(()), recv_ready(&rcv));
+    }
+
+    #[test]
+    fn future_block_accounts_for_wraparound() {
+        assert!(is_future_block(1, 5));
+        assert!(is_future_block(65535, 0));
+        assert!(!is_future_block(1, 1));
+        assert!(!is_future_block(5, 1));
+        assert!(!is_future_block(2, 65535));
+    }
+
+    #[test]
+    fn prev_block_id_follows_rollover_policy() {
+        let mut opts: TransferOptions = Default::default();
+        assert_eq!(4, prev_block_id(&opts, 5));
+        assert_eq!(65535, prev_block_id(&opts, 0));
+        opts.rollover = ToOne;
+        assert_eq!(65535, prev_block_id(&opts, 1));
+        for &id in [1u16, 2, 65534, 65535].iter() {
+            assert_eq!(id, prev_block_id(&opts, next_block_id(&opts, id)));
+        }
+    }
+
+    // Returns the given timestamps in order
+    struct ScriptedClock {
+        times: Vec<u64>
+    }
+
+    impl Clock for ScriptedClock {
+        fn now(&mut self) -> u64 {
+            self.times.remove(0).expect("clock read more often than scripted")
+        }
+    }
+
+    fn scripted_rtt(times: &[u64]) -> RttEstimator {
+        RttEstimator::with_clock(box ScriptedClock { times: Vec::from_slice(times) })
+    }
+
+    #[test]
+    fn rtt_estimate_is_smoothed_over_samples() {
+        let mut rtt = scripted_rtt([1000, 9000, 10000, 26000]);
+        rtt.on_send();
+        rtt.on_receive();
+        assert_eq!(Some(8000), rtt.srtt);
+
+        rtt.on_send();
+        rtt.on_receive();
+        assert_eq!(Some(9000), rtt.srtt);
+    }
+
+    #[test]
+    fn rtt_estimate_ignores_replies_to_resent_packets() {
+        let mut rtt = scripted_rtt([0, 5000, 6000, 10000, 12000]);
+        rtt.on_send();
+        rtt.on_send();
+        rtt.on_receive();
+        assert_eq!(None, rtt.srtt);
+
+        rtt.on_send();
+        rtt.on_receive();
+        assert_eq!(Some(2000), rtt.srtt);
+    }
+
+    #[test]
+    fn rtt_estimate_starts_over_after_reset_with_the_same_clock() {
+        let mut rtt = scripted_rtt([100, 400, 1000, 1050]);
+        rtt.on_send();
+        rtt.on_receive();
+        assert_eq!(Some(300), rtt.srtt);
+
+        rtt.reset();
+        assert_eq!(None, rtt.srtt);
+        rtt.on_send();
+        rtt.on_receive();
+        assert_eq!(Some(50), rtt.srtt);
+    }
+
+    #[test]
+    fn default_options_produce_classic_request() {
+        let opts: TransferOptions = Default::default();
+        assert!(!opts.has_non_default_options());
+        assert!(opts.to_options().is_empty());
+    }
+
+    #[test]
+    fn changed_wire_option_is_non_default() {
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 1;
+        assert!(!opts.has_non_default_options());
+        opts.block_size = 1024;
+        assert!(opts.has_non_default_options());
+        assert_eq!(1, opts.to_options().len());
+    }
+
+    #[test]
+    fn rollover_policy_round_trips_through_options() {
+        for &policy in [Unset, ToZero, ToOne].iter() {
+            let mut opts: TransferOptions = Default::default();
+            opts.rollover = policy;
+            let map = opts.to_options();
+            assert_eq!(policy != Unset, map.contains_key(&"rollover".to_string()));
+            assert_eq!(policy, TransferOptions::from_map(&map).rollover);
+        }
+        let mut map = HashMap::new();
+        map.insert("rollover".to_string(), "0".to_string());
+        assert_eq!(ToZero, TransferOptions::from_map(&map).rollover);
+        map.insert("rollover".to_string(), "1".to_string());
+        assert_eq!(ToOne, TransferOptions::from_map(&map).rollover);
+    }
+
+    #[test]
+    fn progress_is_logged_every_interval_blocks() {
+        let mut opts: TransferOptions = Default::default();
+        assert_eq!(None, progress_log(&opts, 4, 2048));
+        opts.progress_log_interval = 4;
+        opts.transfer_size = Some(5120);
+        let logged: Vec<u64> = range(1u64, 11).filter(|&blocks| {
+            progress_log(&opts, blocks, blocks * 512).is_some()
+        }).collect();
+        assert_eq!(vec![4u64, 8], logged);
+        assert_eq!(Some("transferred 4096 of 5120 bytes (80%)".to_string()), progress_log(&opts, 8, 4096));
+        opts.transfer_size = None;
+        assert_eq!(Some("transferred 2048 bytes".to_string()), progress_log(&opts, 4, 2048));
+    }
+
+    #[test]
+    fn out_of_range_block_size_falls_back_to_default() {
+        let mut map = HashMap::new();
+        map.insert("blksize".to_string(), "0".to_string());
+        assert_eq!(DEFAULT_BLOCK_SIZE, TransferOptions::from_map(&map).block_size);
+        map.insert("blksize".to_string(), "100000".to_string());
+        assert_eq!(DEFAULT_BLOCK_SIZE, TransferOptions::from_map(&map).block_size);
+        let opts: TransferOptions = Default::default();
+        let err = opts.check_block_size(&map).unwrap_err();
+        assert_eq!("Acknowledged block size is out of range", err.desc);
+        map.insert("blksize".to_string(), "65464".to_string());
+        assert_eq!(65464, TransferOptions::from_map(&map).block_size);
+        assert!(opts.check_block_size(&map).is_ok());
+    }
+
+    #[test]
+    fn window_size_round_trips_through_options() {
+        let mut opts: TransferOptions = Default::default();
+        opts.window_size = 8;
+        let map = opts.to_options();
+        assert_eq!(Some(&"8".to_string()), map.find(&"windowsize".to_string()));
+        assert_eq!(8, TransferOptions::from_map(&map).window_size);
+    }
+
+    #[test]
+    fn negotiated_window_size_does_not_exceed_requested() {
+        let mut opts: TransferOptions = Default::default();
+        opts.window_size = 8;
+        let mut map = HashMap::new();
+        assert_eq!(1, opts.negotiate(&map).window_size);
+        map.insert("windowsize".to_string(), "4".to_string());
+        assert_eq!(4, opts.negotiate(&map).window_size);
+        map.insert("windowsize".to_string(), "16".to_string());
+        assert_eq!(8, opts.negotiate(&map).window_size);
+    }
+
+    #[test]
+    fn for_mtu_subtracts_header_overhead() {
+        assert_eq!(1468, TransferOptions::for_mtu(1500).block_size);
+        assert_eq!(8968, TransferOptions::for_mtu(9000).block_size);
+    }
+
+    #[test]
+    fn for_mtu_clamps_block_size_to_valid_range() {
+        assert_eq!(8, TransferOptions::for_mtu(20).block_size);
+        assert_eq!(65464, TransferOptions::for_mtu(65535).block_size);
+    }
+
+    #[test]
+    fn summary_logging_is_kept_after_negotiation() {
+        let mut opts: TransferOptions = Default::default();
+        assert!(opts.log_summary);
+        opts.log_summary = false;
+        assert!(!opts.negotiate(&HashMap::new()).log_summary);
+    }
+
+    #[test]
+    fn negotiated_retry_follows_acknowledged_timeout() {
+        let mut opts: TransferOptions = Default::default();
+        opts.resend_timeout = 7;
+        assert_eq!(Some(7), NegotiatedRetry.next_delay(1, &opts));
+        let mut acked = HashMap::new();
+        acked.insert("timeout".to_string(), "3".to_string());
+        assert_eq!(Some(3), NegotiatedRetry.next_delay(1, &opts.negotiate(&acked)));
+    }
+
+    #[test]
+    fn validate_accepts_transfer_size_beyond_block_id_range() {
+        // Block ids wrap to 0 when no rollover policy is set
+        let mut opts: TransferOptions = Default::default();
+        opts.transfer_size = Some(65535 * 512);
+        assert!(opts.validate().is_ok());
+
+        opts.rollover = ToOne;
+        assert!(opts.validate().is_ok());
+    }
+
+    fn assert_invalid(opts: &TransferOptions, desc: &str) {
+        let err = opts.validate().unwrap_err();
+        assert_eq!(io::InvalidInput, err.kind);
+        assert_eq!(desc, err.desc);
+    }
+
+    #[test]
+    fn from_kv_parses_known_options() {
+        let opts = TransferOptions::from_kv([("blksize", "1024"), ("timeout", "3"), ("tsize", "100"),
+                                             ("rollover", "1"), ("mode", "netascii"), ("window", "4")]).unwrap();
+        assert_eq!(1024, opts.block_size);
+        assert_eq!(3, opts.resend_timeout);
+        assert_eq!(Some(100), opts.transfer_size);
+        assert_eq!(ToOne, opts.rollover);
+        assert_eq!(NetAscii, opts.mode);
+        assert_eq!(4, opts.window_size);
+    }
+
+    #[test]
+    fn from_kv_rejects_unknown_keys_and_bad_values() {
+        let err = TransferOptions::from_kv([("blocksize", "1024")]).unwrap_err();
+        assert_eq!("Unknown option", err.desc);
+        let err = TransferOptions::from_kv([("blksize", "big")]).unwrap_err();
+        assert_eq!("Invalid option value", err.desc);
+        assert_eq!(Some("blksize=big".to_string()), err.detail);
+        let err = TransferOptions::from_kv([("rollover", "2")]).unwrap_err();
+        assert_eq!("Invalid option value", err.desc);
+        let err = TransferOptions::from_kv([("blksize", "4")]).unwrap_err();
+        assert_eq!("Block size is out of range", err.desc);
+    }
+
+    #[test]
+    fn builder_sets_wire_options() {
+        let opts = TransferOptionsBuilder::new()
+            .block_size(1024)
+            .timeout(3)
+            .transfer_size(100)
+            .rollover(ToZero)
+            .mode(NetAscii)
+            .build().unwrap();
+        assert_eq!(1024, opts.block_size);
+        assert_eq!(3, opts.resend_timeout);
+        assert_eq!(Some(100), opts.transfer_size);
+        assert_eq!(ToZero, opts.rollover);
+        assert_eq!(NetAscii, opts.mode);
+    }
+
+    #[test]
+    fn builder_rejects_block_size_out_of_range() {
+        assert!(TransferOptionsBuilder::new().block_size(8).build().is_ok());
+        assert!(TransferOptionsBuilder::new().block_size(65464).build().is_ok());
+        let err = TransferOptionsBuilder::new().block_size(7).build().unwrap_err();
+        assert_eq!("Block size is out of range", err.desc);
+        assert!(TransferOptionsBuilder::new().block_size(65465).build().is_err());
+    }
+
+    #[test]
+    fn validate_accepts_default_options() {
+        let opts: TransferOptions = Default::default();
+        assert!(opts.validate().is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_block_size_out_of_range() {
+        let mut opts: TransferOptions = Default::default();
+        opts.block_size = 7;
+        assert_invalid(&opts, "Block size is out of range");
+        opts.block_size = 65465;
+        assert_invalid(&opts, "Block size is out of range");
+    }
+
+    #[test]
+    fn validate_rejects_zero_receive_timeout() {
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 0;
+        assert_invalid(&opts, "Receive timeout must be positive");
+    }
+
+    #[test]
+    fn validate_accepts_receive_timeout_shorter_than_resend_timeout() {
+        let mut opts: TransferOptions = Default::default();
+        opts.receive_timeout = 1;
+        assert_eq!(Ok(()), opts.validate());
+    }
+
+    #[test]
+    fn validate_rejects_zero_resend_timeout() {
+        let mut opts: TransferOptions = Default::default();
+        opts.resend_timeout = 0;
+        assert_invalid(&opts, "Resend timeout must be positive");
+    }
+
+    #[test]
+    fn validate_rejects_zero_transfer_timeout() {
+        let mut opts: TransferOptions = Default::default();
+        opts.transfer_timeout = Some(0);
+        assert_invalid(&opts, "Transfer timeout must be positive");
+    }
+
+    #[test]
+    fn validate_rejects_zero_error_resend_count() {
+        let mut opts: TransferOptions = Default::default();
+        opts.error_resend_count = 0;
+        assert_invalid(&opts, "Error resend count must be positive");
+    }
+
+    #[test]
+    fn validate_rejects_zero_window_size() {
+        let mut opts: TransferOptions = Default::default();
+        opts.window_size = 0;
+        assert_invalid(&opts, "Window size must be positive");
+    }
+
+}
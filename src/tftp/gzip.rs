@@ -0,0 +1,272 @@
+use std::io;
+use std::io::{IoResult, IoError, MemReader};
+
+use flate;
+
+static MAGIC: [u8, ..2] = [0x1f, 0x8b];
+static DEFLATE: u8 = 8;
+
+static FHCRC: u8 = 0x02;
+static FEXTRA: u8 = 0x04;
+static FNAME: u8 = 0x08;
+static FCOMMENT: u8 = 0x10;
+
+// Extra field holding the length of the deflated data of a member, so that
+// the members of a stream compressed by `Compressor` can be told apart
+static LENGTH_FIELD: [u8, ..2] = [b'T', b'F'];
+
+/// Uncompressed bytes in each member written by `Compressor`.
+pub static MEMBER_SIZE: uint = 64 * 1024;
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for &byte in data.iter() {
+        crc = crc ^ byte as u32;
+        for _ in range(0u, 8) {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn push_le_u32(buf: &mut Vec<u8>, n: u32) {
+    for i in range(0u, 4) {
+        buf.push((n >> (8 * i)) as u8);
+    }
+}
+
+fn read_le_u32(buf: &[u8]) -> u32 {
+    range(0u, 4).fold(0u32, |n, i| n | (buf[i] as u32 << (8 * i)))
+}
+
+fn read_le_u16(buf: &[u8]) -> uint {
+    buf[0] as uint | buf[1] as uint << 8
+}
+
+fn invalid(desc: &'static str) -> IoError {
+    IoError {
+        kind: io::InvalidInput,
+        desc: desc,
+        detail: None
+    }
+}
+
+pub fn compress(data: &[u8]) -> IoResult<Vec<u8>> {
+    let deflated = try!(flate::deflate_bytes(data).ok_or(invalid("Compressing data failed")));
+    let mut buf = Vec::from_slice(MAGIC);
+    // Method, extra field, no modification time, no extra flags, unknown OS
+    buf.push_all([DEFLATE, FEXTRA, 0, 0, 0, 0, 0, 255]);
+    buf.push_all([8, 0]);
+    buf.push_all(LENGTH_FIELD);
+    buf.push_all([4, 0]);
+    push_le_u32(&mut buf, deflated.len() as u32);
+    buf.push_all(deflated.as_slice());
+    push_le_u32(&mut buf, crc32(data));
+    push_le_u32(&mut buf, data.len() as u32);
+    Ok(buf)
+}
+
+/// Decompresses all members of `data`. Only the last one may lack the length
+/// field written by `compress`.
+pub fn decompress(data: &[u8]) -> IoResult<Vec<u8>> {
+    let mut inflated = Vec::new();
+    let mut rest = data;
+    loop {
+        let len = try!(decompress_member(rest, &mut inflated));
+        rest = rest.slice_from(len);
+        if rest.is_empty() {
+            return Ok(inflated)
+        }
+    }
+}
+
+// Appends the data of the member at the start of `data` to `out`, returns the
+// length of the member
+fn decompress_member(data: &[u8], out: &mut Vec<u8>) -> IoResult<uint> {
+    if data.len() < 18 || data.slice_to(2) != MAGIC.as_slice() || data[2] != DEFLATE {
+        return Err(invalid("Not gzip compressed data"))
+    }
+    let flags = data[3];
+    let mut pos = 10;
+    let mut end = data.len() - 8;
+    if flags & FEXTRA != 0 {
+        if pos + 2 > end {
+            return Err(invalid("Truncated gzip header"))
+        }
+        let extra_end = pos + 2 + read_le_u16(data.slice_from(pos));
+        pos += 2;
+        while pos + 4 <= extra_end && extra_end <= end {
+            let len = read_le_u16(data.slice_from(pos + 2));
+            if data.slice(pos, pos + 2) == LENGTH_FIELD.as_slice() && len == 4 && pos + 8 <= extra_end {
+                let deflated_end = extra_end + read_le_u32(data.slice_from(pos + 4)) as uint;
+                if deflated_end > end {
+                    return Err(invalid("Truncated gzip member"))
+                }
+                end = deflated_end;
+            }
+            pos += 4 + len;
+        }
+        pos = extra_end;
+    }
+    for &flag in [FNAME, FCOMMENT].iter() {
+        if flags & flag != 0 {
+            while pos < end && data[pos] != 0 {
+                pos += 1;
+            }
+            pos += 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        pos += 2;
+    }
+    if pos > end {
+        return Err(invalid("Truncated gzip header"))
+    }
+    let inflated = try!(flate::inflate_bytes(data.slice(pos, end)).ok_or(invalid("Decompressing data failed")));
+    let trailer = data.slice(end, end + 8);
+    if read_le_u32(trailer) != crc32(inflated.as_slice())
+        || read_le_u32(trailer.slice_from(4)) != inflated.len() as u32 {
+        return Err(invalid("Decompressed data does not match the gzip checksum"))
+    }
+    out.push_all(inflated.as_slice());
+    Ok(end + 8)
+}
+
+/// Compresses a stream as a series of gzip members of `MEMBER_SIZE`
+/// uncompressed bytes each, so only one member is held in memory at a time.
+pub struct Compressor {
+    member: MemReader,
+    // Uncompressed bytes still allowed to be read from the source
+    limit: Option<u64>,
+    consumed: u64,
+    done: bool
+}
+
+impl Compressor {
+    /// Compresses at most `limit` bytes of the source, if given.
+    pub fn new(limit: Option<u64>) -> Compressor {
+        Compressor {
+            member: MemReader::new(Vec::new()),
+            limit: limit,
+            consumed: 0,
+            done: false
+        }
+    }
+
+    /// Uncompressed bytes read from the source so far.
+    pub fn consumed(&self) -> u64 {
+        self.consumed
+    }
+
+    /// The compressed stream of `source`, which must be the same source on
+    /// every call.
+    pub fn reader<'a>(&'a mut self, source: &'a mut Reader) -> CompressingReader<'a> {
+        CompressingReader {
+            compressor: self,
+            source: source
+        }
+    }
+
+    // Compresses the next member from `source`, false once it is exhausted
+    fn next_member(&mut self, source: &mut Reader) -> IoResult<bool> {
+        if self.done {
+            return Ok(false)
+        }
+        let size = match self.limit {
+            Some(limit) if limit - self.consumed < MEMBER_SIZE as u64 => (limit - self.consumed) as uint,
+            _ => MEMBER_SIZE
+        };
+        let mut chunk = Vec::with_capacity(size);
+        while chunk.len() < size {
+            match source.push(size - chunk.len(), &mut chunk) {
+                Ok(_) => {}
+                Err(ref err) if err.kind == io::EndOfFile => break,
+                Err(err) => return Err(err)
+            }
+        }
+        self.done = chunk.len() < MEMBER_SIZE;
+        // An empty source still needs one member to be valid gzip data
+        if chunk.is_empty() && self.consumed > 0 {
+            return Ok(false)
+        }
+        self.consumed += chunk.len() as u64;
+        self.member = MemReader::new(try!(compress(chunk.as_slice())));
+        Ok(true)
+    }
+}
+
+pub struct CompressingReader<'a> {
+    compressor: &'a mut Compressor,
+    source: &'a mut Reader
+}
+
+impl<'a> Reader for CompressingReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        loop {
+            match self.compressor.member.read(buf) {
+                Err(ref err) if err.kind == io::EndOfFile => {}
+                res => return res
+            }
+            if !try!(self.compressor.next_member(self.source)) {
+                return Err(io::standard_error(io::EndOfFile))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use super::{compress, decompress, crc32, Compressor, MEMBER_SIZE};
+
+    #[test]
+    fn crc32_of_check_string() {
+        assert_eq!(0xcbf43926, crc32(b"123456789"));
+    }
+
+    #[test]
+    fn compressed_data_round_trips() {
+        let data = Vec::from_fn(10000, |i| (i % 7) as u8);
+        let compressed = compress(data.as_slice()).unwrap();
+        assert!(compressed.len() < data.len());
+        assert_eq!(data, decompress(compressed.as_slice()).unwrap());
+    }
+
+    #[test]
+    fn compressor_writes_members_that_decompress_to_the_source() {
+        let data = Vec::from_fn(MEMBER_SIZE * 2 + 100, |i| (i % 7) as u8);
+        let mut source = io::BufReader::new(data.as_slice());
+        let mut compressor = Compressor::new(None);
+        let compressed = compressor.reader(&mut source).read_to_end().unwrap();
+        assert_eq!(data.len() as u64, compressor.consumed());
+        assert_eq!(data, decompress(compressed.as_slice()).unwrap());
+    }
+
+    #[test]
+    fn compressor_reads_no_more_than_the_limit() {
+        let data = Vec::from_fn(MEMBER_SIZE + 100, |i| (i % 7) as u8);
+        let mut source = io::BufReader::new(data.as_slice());
+        let mut compressor = Compressor::new(Some(MEMBER_SIZE as u64 + 10));
+        let compressed = compressor.reader(&mut source).read_to_end().unwrap();
+        assert_eq!(MEMBER_SIZE as u64 + 10, compressor.consumed());
+        assert_eq!(data.slice_to(MEMBER_SIZE + 10), decompress(compressed.as_slice()).unwrap().as_slice());
+    }
+
+    #[test]
+    fn compressor_of_empty_source_is_valid_gzip() {
+        let mut source = io::BufReader::new([]);
+        let mut compressor = Compressor::new(None);
+        let compressed = compressor.reader(&mut source).read_to_end().unwrap();
+        assert_eq!(Vec::new(), decompress(compressed.as_slice()).unwrap());
+    }
+
+    #[test]
+    fn decompress_rejects_corrupted_data() {
+        let mut compressed = compress(b"some data").unwrap();
+        assert!(decompress(compressed.slice_from(1)).is_err());
+        let last = compressed.len() - 5;
+        *compressed.get_mut(last) ^= 1;
+        assert!(decompress(compressed.as_slice()).is_err());
+    }
+}
@@ -6,6 +6,19 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+//! TFTP client, server and packet codec.
+//!
+//! The types needed to configure a transfer and to inspect protocol errors are
+//! available from the crate root:
+//!
+//! ```
+//! use tftp::{TransferOptions, NetAscii, FileNotFound};
+//!
+//! let mut opts: TransferOptions = ::std::default::Default::default();
+//! opts.mode = NetAscii;
+//! assert!(FileNotFound != tftp::AccessViolation);
+//! ```
+
 #![crate_id = "tftp"]
 #![license = "MIT/ASL2"]
 #![crate_type = "rlib"]
@@ -13,13 +26,27 @@
 #![feature(macro_rules, phase)]
 
 extern crate collections;
+extern crate flate;
 extern crate rand;
+extern crate time;
 #[phase(plugin, link)] extern crate log;
 
-pub use common::TransferOptions;
+pub use common::{TransferOptions, TransferOptionsBuilder, TransferStats, TransferSummary, Direction, Sent, Received};
+pub use common::{RetryStrategy, NegotiatedRetry, FixedRetry, ExponentialBackoff};
+pub use common::{TimeoutCause, NoResponse, TransferStalled, RetriesExhausted, DeadlineExceeded};
+pub use common::{TftpError, Protocol, Timeout, Io};
+pub use common::{Control, Pause, Resume};
+pub use protocol::{Mode, NetAscii, Octet};
+pub use protocol::{RolloverPolicy, Unset, ToZero, ToOne};
+pub use protocol::{Error, Undefined, FileNotFound, AccessViolation, DiskFull, IllegalOperation};
+pub use protocol::{UnknownTransferId, FileAlreadyExists, NoSuchUser, OptionNegotiationRejected};
+pub use util::{bind_socket_with, random_ephemeral_port, BIND_ATTEMPTS};
+pub use util::{bind_socket_with_rng, random_ephemeral_port_from};
 
 pub mod protocol;
 
 mod util;
 mod common;
+mod gzip;
 pub mod client;
+pub mod server;
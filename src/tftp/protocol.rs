@@ -5,10 +5,13 @@ use std::str;
 use std::fmt;
 use std::from_str;
 use std::ascii::StrAsciiExt;
+use std::default::Default;
 
 use std::collections::hashmap::HashMap;
 
 pub static DEFAULT_BLOCK_SIZE: uint = 512;
+pub static MIN_BLOCK_SIZE: uint = 8;
+pub static MAX_BLOCK_SIZE: uint = 65464;
 
 #[deriving(Show, Eq, PartialEq, Clone)]
 pub enum Opcode {
@@ -20,6 +23,20 @@ pub enum Opcode {
     OACK  = 0x06
 }
 
+impl Opcode {
+    pub fn from_u16(n: u16) -> Option<Opcode> {
+        match n {
+            0x01 => Some(RRQ),
+            0x02 => Some(WRQ),
+            0x03 => Some(DATA),
+            0x04 => Some(ACK),
+            0x05 => Some(ERROR),
+            0x06 => Some(OACK),
+            _ => None
+        }
+    }
+}
+
 #[deriving(Eq, PartialEq, Clone)]
 pub enum Mode {
     NetAscii,
@@ -37,7 +54,7 @@ impl fmt::Show for Mode {
 
 impl from_str::FromStr for Mode {
     fn from_str(s: &str) -> Option<Mode> {
-        match s {
+        match s.to_ascii_lower().as_slice() {
             "netascii" => Some(NetAscii),
             "octet" => Some(Octet),
             _ => None
@@ -45,32 +62,44 @@ impl from_str::FromStr for Mode {
     }
 }
 
+/// What the block id wraps to after 65535. `Unset` sends no `rollover`
+/// option and wraps to 0, like most implementations do.
 #[deriving(Clone, Eq, PartialEq)]
-pub enum RolloverMethod {
-    Zero = 0u16,
-    One  = 1u16
+pub enum RolloverPolicy {
+    Unset,
+    ToZero,
+    ToOne
+}
+
+impl RolloverPolicy {
+    pub fn first_block(&self) -> BlockId {
+        match *self {
+            ToOne => 1,
+            Unset | ToZero => 0
+        }
+    }
 }
 
-impl from_str::FromStr for RolloverMethod {
-    fn from_str(s: &str) -> Option<RolloverMethod> {
+impl from_str::FromStr for RolloverPolicy {
+    fn from_str(s: &str) -> Option<RolloverPolicy> {
         match s {
-            "0" => Some(Zero),
-            "1" => Some(One),
+            "0" => Some(ToZero),
+            "1" => Some(ToOne),
             _ => None
         }
     }
 }
 
-impl fmt::Show for RolloverMethod {
+impl fmt::Show for RolloverPolicy {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            Zero => write!(fmt, "0"),
-            One => write!(fmt, "1")
+            Unset => write!(fmt, "unset"),
+            ToZero => write!(fmt, "0"),
+            ToOne => write!(fmt, "1")
         }
     }
 }
 
-
 #[deriving(Show, Eq, PartialEq, Clone)]
 pub enum Error {
     Undefined                 = 0x00,
@@ -101,6 +130,43 @@ impl Error {
     }
 }
 
+/// Line ending that a netascii CR LF is decoded to.
+#[deriving(Show, Eq, PartialEq, Clone)]
+pub enum Newline {
+    Lf,
+    CrLf
+}
+
+impl Newline {
+    /// Line ending of the platform this is built for.
+    pub fn native() -> Newline {
+        if cfg!(windows) { CrLf } else { Lf }
+    }
+
+    fn bytes(&self) -> &'static [u8] {
+        match *self {
+            Lf => b"\n",
+            CrLf => b"\r\n"
+        }
+    }
+}
+
+impl Default for Newline {
+    fn default() -> Newline {
+        Lf
+    }
+}
+
+/// Leniency switches for `Packet::decode_with`.
+#[deriving(Show, Clone, Default)]
+pub struct DecodeSettings {
+    pub lossy_filenames: bool,
+    /// Keep option names as sent by the peer instead of lowercasing them.
+    pub preserve_option_case: bool,
+    /// Line ending written for netascii line breaks in Data packets.
+    pub newline: Newline
+}
+
 pub type Filename = String;
 pub type BlockId = u16;
 pub type Options = HashMap<String, String>;
@@ -200,64 +266,134 @@ impl Packet {
     }
 
     fn encode_netascii(w: &mut MemWriter, data: &[u8]) -> IoResult<()> {
-        for b in data.iter() {
-            if *b == '\n' as u8 {
-                try!(w.write_str("\r\n"))
-            } else if *b == '\r' as u8 {
-                try!(w.write_str("\r\0"))
-            } else {
-                try!(w.write_u8(*b))
+        let mut rest = data;
+        loop {
+            match rest.iter().position(|&b| b == b'\n' || b == b'\r') {
+                Some(i) => {
+                    try!(w.write(rest.slice_to(i)));
+                    if rest[i] == b'\n' {
+                        try!(w.write(b"\r\n"))
+                    } else {
+                        try!(w.write(b"\r\0"))
+                    }
+                    rest = rest.slice_from(i + 1);
+                }
+                None => return w.write(rest)
             }
         }
-        return Ok(())
     }
 
     pub fn decode(mode: Mode, p: &[u8]) -> IoResult<Packet> {
+        Packet::decode_with(mode, p, &Default::default())
+    }
+
+    /// Like `decode`, but filenames that are not valid UTF-8 are decoded lossily
+    /// instead of rejecting the whole packet.
+    pub fn decode_lossy(mode: Mode, p: &[u8]) -> IoResult<Packet> {
+        Packet::decode_with(mode, p, &DecodeSettings { lossy_filenames: true, ..Default::default() })
+    }
+
+    /// Reads only the opcode and, for Data and Acknowledgment packets, the block
+    /// id, without decoding or copying the rest of the datagram.
+    pub fn peek_header(bytes: &[u8]) -> Option<(Opcode, Option<BlockId>)> {
+        let read_u16 = |i: uint| (bytes[i] as u16 << 8) | bytes[i + 1] as u16;
+        if bytes.len() < 2 {
+            return None
+        }
+        let opcode = match Opcode::from_u16(read_u16(0)) {
+            Some(opcode) => opcode,
+            None => return None
+        };
+        match opcode {
+            DATA | ACK if bytes.len() < 4 => None,
+            DATA | ACK => Some((opcode, Some(read_u16(2)))),
+            _ => Some((opcode, None))
+        }
+    }
+
+    pub fn decode_with(mode: Mode, p: &[u8], settings: &DecodeSettings) -> IoResult<Packet> {
         let mut buf = BufReader::new(p);
-        let opcode = try!(buf.read_be_u16());
-        if opcode == RRQ as u16 {
-            Packet::decode_request(&mut buf, |fname, mode, opts| ReadRequest(fname, mode, opts))
-        } else if opcode == WRQ as u16 {
-            Packet::decode_request(&mut buf, |fname, mode, opts| WriteRequest(fname, mode, opts))
-        } else if opcode == DATA as u16 {
-            let block_id = try!(buf.read_be_u16());
-            let data = try!(if mode == NetAscii {
-                Packet::decode_netascii(&mut buf)
-            } else {
-                buf.read_to_end()
-            });
-            Ok(Data(block_id, data))
-        } else if opcode == ACK as u16 {
-            let block_id = try!(buf.read_be_u16());
-            Ok(Acknowledgment(block_id))
-        } else if opcode == ERROR as u16 {
-            let error_code = try!(buf.read_be_u16());
-            let error_msg = try!(Packet::read_str(&mut buf));
-            match Error::from_u16(error_code) {
-                Some(err) => Ok(Error(err, error_msg)),
-                None => invalid_input_error("Invalid error code")
+        let opcode = try!(Packet::read_header_u16(&mut buf));
+        match Opcode::from_u16(opcode) {
+            Some(RRQ) => {
+                Packet::decode_request(&mut buf, settings, |fname, mode, opts| ReadRequest(fname, mode, opts))
             }
-        } else if opcode == OACK as u16 {
-            let opts = Packet::decode_options(&mut buf);
-            Ok(OptionAcknowledgment(opts))
-        } else {
-            invalid_input_error("Wrong packet type")
+            Some(WRQ) => {
+                Packet::decode_request(&mut buf, settings, |fname, mode, opts| WriteRequest(fname, mode, opts))
+            }
+            Some(DATA) => {
+                let block_id = try!(Packet::read_header_u16(&mut buf));
+                let data = try!(if mode == NetAscii {
+                    Packet::decode_netascii(&mut buf, settings.newline)
+                } else {
+                    buf.read_to_end()
+                });
+                Ok(Data(block_id, data))
+            }
+            Some(ACK) => {
+                let block_id = try!(Packet::read_header_u16(&mut buf));
+                if !buf.eof() {
+                    return invalid_input_error("Trailing bytes after acknowledgment")
+                }
+                Ok(Acknowledgment(block_id))
+            }
+            Some(ERROR) => {
+                let error_code = try!(Packet::read_header_u16(&mut buf));
+                let error_msg = try!(Packet::read_str(&mut buf));
+                match Error::from_u16(error_code) {
+                    Some(err) => Ok(Error(err, error_msg)),
+                    None => invalid_input_error("Invalid error code")
+                }
+            }
+            Some(OACK) => {
+                let opts = try!(Packet::decode_options(&mut buf, settings));
+                Ok(OptionAcknowledgment(opts))
+            }
+            None => invalid_input_error("Wrong packet type")
         }
     }
 
-    fn decode_request(buf: &mut BufReader, f: |Filename, Mode, Options| -> Packet) -> IoResult<Packet> {
-        let filename = try!(Packet::read_str(buf));
-        let mode_name = try!(Packet::read_str(buf));
-        let opts = Packet::decode_options(buf);
+    fn decode_request(buf: &mut BufReader, settings: &DecodeSettings, f: |Filename, Mode, Options| -> Packet) -> IoResult<Packet> {
+        let filename_bytes = try!(Packet::read_required_field(buf, "Filename is not terminated"));
+        let filename = if settings.lossy_filenames {
+            str::from_utf8_lossy(filename_bytes.as_slice()).into_string()
+        } else {
+            try!(Packet::bytes_to_str(filename_bytes))
+        };
+        let mode_bytes = try!(Packet::read_required_field(buf, "Mode is missing or not terminated"));
+        let mode_name = try!(Packet::bytes_to_str(mode_bytes));
+        let opts = try!(Packet::decode_options(buf, settings));
         match from_str::<Mode>(mode_name.as_slice()) {
             Some(mode) => Ok(f(filename, mode, opts)),
             None => invalid_input_error("Mode not recognized")
         }
     }
 
+    fn read_header_u16(buf: &mut BufReader) -> IoResult<u16> {
+        match buf.read_be_u16() {
+            Ok(n) => Ok(n),
+            Err(_) => invalid_input_error("Packet header is truncated")
+        }
+    }
+
+    /// Reads a NUL-terminated field that the packet can not be decoded without.
+    fn read_required_field(buf: &mut BufReader, desc: &'static str) -> IoResult<Vec<u8>> {
+        match Packet::read_field(buf, 0) {
+            Ok((field, true)) => Ok(field),
+            _ => invalid_input_error(desc)
+        }
+    }
+
     fn read_to(buf: &mut BufReader, byte: u8) -> IoResult<Vec<u8>> {
+        Packet::read_field(buf, byte).map(|(res, _)| res)
+    }
+
+    /// Reads up to the `byte` terminator or the end of the buffer, also returning
+    /// whether the terminator was found.
+    fn read_field(buf: &mut BufReader, byte: u8) -> IoResult<(Vec<u8>, bool)> {
         let mut res = Vec::new();
 
+        let mut terminated = false;
         let mut used;
         loop {
             {
@@ -273,6 +409,7 @@ impl Packet {
                     Some(i) => {
                         res.push_all(available.slice_to(i));
                         used = i + 1;
+                        terminated = true;
                         break
                     }
                     None => {
@@ -284,54 +421,227 @@ impl Packet {
             buf.consume(used);
         }
         buf.consume(used);
-        Ok(res)
+        Ok((res, terminated))
     }
 
     fn read_str(buf: &mut BufReader) -> IoResult<String> {
         let bytes = try!(Packet::read_to(buf, 0));
+        Packet::bytes_to_str(bytes)
+    }
+
+    fn bytes_to_str(bytes: Vec<u8>) -> IoResult<String> {
         match str::from_utf8_owned(bytes.as_slice().to_owned()) {
             Ok(read_str) => Ok(read_str),
             Err(_) => invalid_input_error("Wrong string encoding")
         }
     }
 
-    fn decode_options(buf: &mut BufReader) -> Options {
+    /// A key that appears more than once is rejected instead of letting one of
+    /// the values win silently.
+    fn decode_options(buf: &mut BufReader, settings: &DecodeSettings) -> IoResult<Options> {
         let mut opts = HashMap::new();
         loop {
-            let key_opt = Packet::read_str(buf);
-            let val_opt = Packet::read_str(buf);
-            match (key_opt, val_opt) {
-                (Ok(key), Ok(val)) => { opts.insert(key.as_slice().to_ascii_lower(), val); },
-                _ => break
+            let (key_bytes, key_terminated) = match Packet::read_field(buf, 0) {
+                Ok(field) => field,
+                Err(_) => break
+            };
+            let key = match Packet::bytes_to_str(key_bytes) {
+                Ok(ref key) if !settings.preserve_option_case => key.as_slice().to_ascii_lower(),
+                Ok(key) => key,
+                Err(_) => break
+            };
+            if opts.contains_key(&key) {
+                return invalid_input_error("Duplicate option")
+            }
+            match Packet::read_str(buf) {
+                Ok(val) => { opts.insert(key, val); },
+                // An empty value as the last field, without its terminating NUL
+                Err(ref err) if key_terminated && err.kind == io::EndOfFile => {
+                    opts.insert(key, String::new());
+                    break
+                },
+                Err(_) => break
             }
         }
-        opts
+        Ok(opts)
     }
 
-    fn decode_netascii(buf: &mut BufReader) -> IoResult<Vec<u8>> {
+    fn decode_netascii<B: Buffer>(buf: &mut B, newline: Newline) -> IoResult<Vec<u8>> {
         let mut data = Vec::new();
+        let mut decoder = NetasciiDecoder::new(newline);
         loop {
-            match buf.read_byte() {
-                Ok(b) => {
-                    if b == '\r' as u8 {
-                        let next = try!(buf.read_byte()) as char;
-                        match next {
-                            '\n' => data.push('\n' as u8),
-                            '\0' => data.push('\r' as u8),
-                            _    => return invalid_input_error("Invalid netascii encoding")
-                        }
-                    } else {
-                        data.push(b);
-                    }
+            let used = {
+                let available = match buf.fill_buf() {
+                    Ok(available) => available,
+                    Err(ref err) if err.kind == io::EndOfFile => break,
+                    Err(err) => return Err(err)
+                };
+                try!(decoder.decode_to(available, &mut data));
+                available.len()
+            };
+            buf.consume(used);
+        }
+        try!(decoder.finish());
+        Ok(data)
+    }
+}
+
+/// Decodes a netascii stream that arrives in chunks, such as the payloads of
+/// consecutive Data packets. A CR ending one chunk is decoded together with the
+/// first byte of the next one.
+#[deriving(Show, Clone)]
+pub struct NetasciiDecoder {
+    newline: Newline,
+    // CR was the last byte of the previous chunk
+    pending_cr: bool,
+    // Number of bytes in the previous chunks
+    offset: u64
+}
+
+impl NetasciiDecoder {
+    pub fn new(newline: Newline) -> NetasciiDecoder {
+        NetasciiDecoder {
+            newline: newline,
+            pending_cr: false,
+            offset: 0
+        }
+    }
+
+    pub fn decode(&mut self, chunk: &[u8]) -> IoResult<Vec<u8>> {
+        let mut data = Vec::with_capacity(chunk.len());
+        try!(self.decode_to(chunk, &mut data));
+        Ok(data)
+    }
+
+    /// Like `decode`, but the decoded bytes are appended to `data`.
+    pub fn decode_to(&mut self, chunk: &[u8], data: &mut Vec<u8>) -> IoResult<()> {
+        let mut start = 0;
+        if self.pending_cr && !chunk.is_empty() {
+            try!(self.decode_cr(data, chunk, 0));
+            self.pending_cr = false;
+            start = 1;
+        }
+        loop {
+            let rest = chunk.slice_from(start);
+            match rest.iter().position(|&b| b == b'\r') {
+                Some(i) if start + i + 1 < chunk.len() => {
+                    data.push_all(rest.slice_to(i));
+                    try!(self.decode_cr(data, chunk, start + i + 1));
+                    start += i + 2;
+                }
+                Some(i) => {
+                    data.push_all(rest.slice_to(i));
+                    self.pending_cr = true;
+                    break
                 }
+                None => {
+                    data.push_all(rest);
+                    break
+                }
+            }
+        }
+        self.offset += chunk.len() as u64;
+        Ok(())
+    }
+
+    /// Fails if the stream ended with an incomplete CR sequence.
+    pub fn finish(&self) -> IoResult<()> {
+        if self.pending_cr {
+            Err(IoError {
+                kind: io::InvalidInput,
+                desc: "Invalid netascii encoding",
+                detail: Some(format!("stream ends with CR at offset {}", self.offset - 1))
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    // Decodes the byte at `index` of `chunk` that follows a CR
+    fn decode_cr(&self, data: &mut Vec<u8>, chunk: &[u8], index: uint) -> IoResult<()> {
+        match chunk[index] {
+            b'\n' => data.push_all(self.newline.bytes()),
+            b'\0' => data.push(b'\r'),
+            next => return Err(IoError {
+                kind: io::InvalidInput,
+                desc: "Invalid netascii encoding",
+                detail: Some(format!("CR followed by 0x{:02x} at offset {}",
+                                     next, self.offset + index as u64))
+            })
+        }
+        Ok(())
+    }
+}
+
+/// Netascii encodes the data read from `inner`. Every read fills the buffer
+/// unless the end of `inner` is reached, a CR LF or CR NUL sequence that does
+/// not fit is completed by the next read.
+pub struct NetasciiReader<R> {
+    inner: R,
+    // Second byte of a sequence that did not fit into the previous read
+    pending: Option<u8>
+}
+
+impl<R: Reader> NetasciiReader<R> {
+    pub fn new(inner: R) -> NetasciiReader<R> {
+        NetasciiReader {
+            inner: inner,
+            pending: None
+        }
+    }
+}
+
+impl<R: Reader> Reader for NetasciiReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        let mut len = 0;
+        while len < buf.len() {
+            match self.pending.take() {
+                Some(b) => {
+                    buf[len] = b;
+                    len += 1;
+                    continue
+                }
+                None => {}
+            }
+            // Each byte is encoded to at most two, so only the last one can
+            // be left pending
+            let mut raw = Vec::from_elem((buf.len() - len + 1) / 2, 0u8);
+            let n = match self.inner.read(raw.as_mut_slice()) {
+                Ok(0) => break,
+                Ok(n) => n,
                 Err(ref err) if err.kind == io::EndOfFile => break,
                 Err(err) => return Err(err)
+            };
+            for &b in raw.slice_to(n).iter() {
+                let (first, second) = match b {
+                    b'\n' => (b'\r', Some(b'\n')),
+                    b'\r' => (b'\r', Some(b'\0')),
+                    _ => (b, None)
+                };
+                buf[len] = first;
+                len += 1;
+                match second {
+                    Some(b) if len < buf.len() => {
+                        buf[len] = b;
+                        len += 1;
+                    }
+                    second => self.pending = second
+                }
             }
         }
-        return Ok(data)
+        if len == 0 && !buf.is_empty() {
+            return Err(io::standard_error(io::EndOfFile))
+        }
+        Ok(len)
     }
 }
 
+/// Decodes a batch of datagrams, for example UDP payloads extracted from a
+/// packet capture, with the result for each one in the same order.
+pub fn parse_all(mode: Mode, datagrams: &[&[u8]]) -> Vec<IoResult<Packet>> {
+    datagrams.iter().map(|datagram| Packet::decode(mode, *datagram)).collect()
+}
+
 fn invalid_input_error<T>(desc: &'static str) -> IoResult<T> {
     let err = IoError {
         kind: io::InvalidInput,
@@ -343,8 +653,88 @@ fn invalid_input_error<T>(desc: &'static str) -> IoResult<T> {
 
 #[cfg(test)]
 mod test {
-    use super::{Packet, Octet, NetAscii};
-    use super::{ReadRequest, Data};
+    use std::io;
+    use std::collections::hashmap::HashMap;
+
+    use std::default::Default;
+
+    use super::{Packet, DecodeSettings, Octet, NetAscii};
+    use super::{Newline, Lf, CrLf};
+    use super::{NetasciiDecoder, NetasciiReader};
+    use super::{Opcode, RRQ, WRQ, DATA, ACK, ERROR, OACK};
+    use super::{ReadRequest, WriteRequest, Data, Acknowledgment};
+    use super::parse_all;
+
+    #[test]
+    fn parse_all_reports_result_for_each_datagram() {
+        let datagrams = [b"\x00\x04\x00\x07", b"\x00\x99", b"\x00\x03\x00\x01a", b"\x00\x05\x00\x63\x00"];
+        let results = parse_all(Octet, datagrams);
+        assert_eq!(4, results.len());
+        assert_eq!(&Ok(Acknowledgment(7)), results.get(0));
+        assert!(results.get(1).is_err());
+        assert_eq!(&Ok(Data(1, vec![b'a'])), results.get(2));
+        assert_eq!("Invalid error code", results.get(3).as_ref().unwrap_err().desc);
+    }
+
+    #[test]
+    fn decoding_request_rejects_duplicate_option() {
+        let packet_bytes = b"\x00\x01file\x00octet\x00blksize\x001024\x00blksize\x00512\x00";
+        let err = Packet::decode(Octet, packet_bytes).unwrap_err();
+        assert_eq!(io::InvalidInput, err.kind);
+        assert_eq!("Duplicate option", err.desc);
+        // Keys are compared after lowercasing
+        let packet_bytes = b"\x00\x06blksize\x001024\x00BLKSIZE\x00512\x00";
+        assert!(Packet::decode(Octet, packet_bytes).is_err());
+    }
+
+    #[test]
+    fn opcode_from_u16_maps_known_values_only() {
+        assert_eq!(Some(RRQ), Opcode::from_u16(1));
+        assert_eq!(Some(OACK), Opcode::from_u16(6));
+        assert_eq!(None, Opcode::from_u16(0));
+        assert_eq!(None, Opcode::from_u16(99));
+        let err = Packet::decode(Octet, [0u8, 99, 0, 1]).unwrap_err();
+        assert_eq!(io::InvalidInput, err.kind);
+    }
+
+    #[test]
+    fn peek_header_reads_opcode_and_block_id() {
+        assert_eq!(Some((RRQ, None)), Packet::peek_header([0u8, 1, b'f', 0]));
+        assert_eq!(Some((WRQ, None)), Packet::peek_header([0u8, 2, b'f', 0]));
+        assert_eq!(Some((DATA, Some(258))), Packet::peek_header([0u8, 3, 1, 2, 0xff, 0xff]));
+        assert_eq!(Some((ACK, Some(7))), Packet::peek_header([0u8, 4, 0, 7]));
+        assert_eq!(Some((ERROR, None)), Packet::peek_header([0u8, 5, 0, 1, 0]));
+        assert_eq!(Some((OACK, None)), Packet::peek_header([0u8, 6]));
+    }
+
+    #[test]
+    fn peek_header_rejects_short_or_unknown_headers() {
+        assert_eq!(None, Packet::peek_header([]));
+        assert_eq!(None, Packet::peek_header([0u8]));
+        assert_eq!(None, Packet::peek_header([0u8, 3, 1]));
+        assert_eq!(None, Packet::peek_header([0u8, 4]));
+        assert_eq!(None, Packet::peek_header([0u8, 0x99, 0, 1]));
+    }
+
+    #[test]
+    fn decoding_rejects_malformed_requests() {
+        let err = Packet::decode(Octet, b"\x00\x01file.txt").unwrap_err();
+        assert_eq!(io::InvalidInput, err.kind);
+        let err = Packet::decode(Octet, b"\x00\x01file.txt\x00").unwrap_err();
+        assert_eq!("Mode is missing or not terminated", err.desc);
+        let err = Packet::decode(Octet, b"\x00\x02file.txt\x00octet").unwrap_err();
+        assert_eq!("Mode is missing or not terminated", err.desc);
+    }
+
+    #[test]
+    fn decoding_rejects_truncated_headers_and_trailing_bytes() {
+        for bytes in [b"\x00", b"\x00\x03\x01", b"\x00\x04", b"\x00\x05\x00"].iter() {
+            let err = Packet::decode(Octet, *bytes).unwrap_err();
+            assert_eq!(io::InvalidInput, err.kind);
+        }
+        let err = Packet::decode(Octet, b"\x00\x04\x00\x01\x00").unwrap_err();
+        assert_eq!("Trailing bytes after acknowledgment", err.desc);
+    }
 
     #[test]
     fn option_names_are_parsed_case_insensitive() {
@@ -358,6 +748,70 @@ mod test {
         }
     }
 
+    #[test]
+    fn option_name_case_can_be_preserved() {
+        let mut packet_bytes = Vec::from_slice([0u8, 1]);
+        packet_bytes.push_all(b"file.ext\0octet\0BlkSize\0512\0");
+        let settings = DecodeSettings { preserve_option_case: true, ..Default::default() };
+        match Packet::decode_with(Octet, packet_bytes.as_slice(), &settings).unwrap() {
+            ReadRequest(_, _, ref opts) => {
+                assert_eq!(opts.find(&"BlkSize".to_string()), Some(&"512".to_string()));
+                assert_eq!(opts.find(&"blksize".to_string()), None);
+            },
+            _ => fail!()
+        }
+        match Packet::decode(Octet, packet_bytes.as_slice()).unwrap() {
+            ReadRequest(_, _, ref opts) => {
+                assert_eq!(opts.find(&"blksize".to_string()), Some(&"512".to_string()));
+            },
+            _ => fail!()
+        }
+    }
+
+    #[test]
+    fn empty_last_option_value_without_terminator_is_parsed() {
+        let mut packet_bytes = Vec::from_slice([0u8, 1]);
+        packet_bytes.push_all(b"file.ext\0octet\0key\0");
+        match Packet::decode(Octet, packet_bytes.as_slice()).unwrap() {
+            ReadRequest(_, _, ref opts) => {
+                assert_eq!(opts.get(&"key".to_string()), &String::new());
+            },
+            _ => fail!()
+        }
+    }
+
+    #[test]
+    fn truncated_last_option_key_is_ignored() {
+        let mut packet_bytes = Vec::from_slice([0u8, 1]);
+        packet_bytes.push_all(b"file.ext\0octet\0key");
+        match Packet::decode(Octet, packet_bytes.as_slice()).unwrap() {
+            ReadRequest(_, _, ref opts) => assert!(opts.is_empty()),
+            _ => fail!()
+        }
+    }
+
+    #[test]
+    fn mode_names_are_parsed_case_insensitive() {
+        let mut packet_bytes = Vec::from_slice([0u8, 1]);
+        packet_bytes.push_all(b"file.ext\0OCTET\0");
+        assert_eq!(Packet::decode(Octet, packet_bytes.as_slice()).unwrap(),
+                   ReadRequest("file.ext".to_string(), Octet, HashMap::new()));
+
+        let mut packet_bytes = Vec::from_slice([0u8, 2]);
+        packet_bytes.push_all(b"file.ext\0NetAscii\0");
+        assert_eq!(Packet::decode(Octet, packet_bytes.as_slice()).unwrap(),
+                   WriteRequest("file.ext".to_string(), NetAscii, HashMap::new()));
+    }
+
+    #[test]
+    fn non_utf8_filenames_are_decoded_in_lossy_mode() {
+        let mut packet_bytes = Vec::from_slice([0u8, 1, b'f', 0xff, b'e']);
+        packet_bytes.push_all(b"\0octet\0");
+        assert!(Packet::decode(Octet, packet_bytes.as_slice()).is_err());
+        assert_eq!(Packet::decode_lossy(Octet, packet_bytes.as_slice()).unwrap(),
+                   ReadRequest("f\ufffde".to_string(), Octet, HashMap::new()));
+    }
+
     #[test]
     fn encoding_and_decoding_data_in_octet_mode() {
         let data = b"CR\rNL\nEND\n";
@@ -368,6 +822,97 @@ mod test {
         assert_eq!(Packet::decode(Octet, packet_bytes.as_slice()).unwrap(), packet);
     }
 
+    #[test]
+    fn netascii_encoding_of_large_payload() {
+        let data = Vec::from_fn(4096, |i| match i % 37 {
+            0 => b'\n',
+            5 => b'\r',
+            _ => (i % 26) as u8 + b'a'
+        });
+        let mut expected = Vec::from_slice([0u8, 3, 0, 1]);
+        for &b in data.iter() {
+            match b {
+                b'\n' => expected.push_all(b"\r\n"),
+                b'\r' => expected.push_all(b"\r\0"),
+                _ => expected.push(b)
+            }
+        }
+        let packet = Data(1, data);
+        assert_eq!(Packet::encode(NetAscii, &packet).unwrap(), expected);
+        assert_eq!(Packet::decode(NetAscii, expected.as_slice()).unwrap(), packet);
+    }
+
+    #[test]
+    fn netascii_decoding_handles_cr_at_buffer_boundary() {
+        for capacity in range(1u, 6) {
+            let inner = io::MemReader::new(Vec::from_slice(b"ab\r\ncd\r\0e"));
+            let mut buf = io::BufferedReader::with_capacity(capacity, inner);
+            assert_eq!(Packet::decode_netascii(&mut buf, Lf).unwrap(), Vec::from_slice(b"ab\ncd\re"));
+        }
+    }
+
+    #[test]
+    fn netascii_decoder_keeps_cr_across_chunks() {
+        let mut decoder = NetasciiDecoder::new(Lf);
+        assert_eq!(Vec::from_slice(b"ab"), decoder.decode(b"ab\r").unwrap());
+        assert!(decoder.finish().is_err());
+        assert_eq!(Vec::from_slice(b"\ncd"), decoder.decode(b"\ncd\r").unwrap());
+        assert_eq!(Vec::from_slice(b"\re"), decoder.decode(b"\0e").unwrap());
+        assert!(decoder.finish().is_ok());
+    }
+
+    #[test]
+    fn netascii_reader_splits_sequences_across_reads() {
+        let mut reader = NetasciiReader::new(io::BufReader::new(b"a\nb\rc"));
+        let mut encoded = Vec::new();
+        let mut lengths = Vec::new();
+        let mut buf = [0u8, ..2];
+        loop {
+            match reader.read(buf) {
+                Ok(len) => {
+                    lengths.push(len);
+                    encoded.push_all(buf.slice_to(len));
+                }
+                Err(err) => {
+                    assert_eq!(io::EndOfFile, err.kind);
+                    break
+                }
+            }
+        }
+        assert_eq!(Vec::from_slice(b"a\r\nb\r\0c"), encoded);
+        assert_eq!(vec![2u, 2, 2, 1], lengths);
+    }
+
+    #[test]
+    fn netascii_decoding_rejects_trailing_cr() {
+        let mut buf = io::BufReader::new(b"ab\r");
+        assert!(Packet::decode_netascii(&mut buf, Lf).is_err());
+    }
+
+    #[test]
+    fn netascii_decoding_error_names_offending_byte() {
+        let err = NetasciiDecoder::new(Lf).decode(b"\r\x41").unwrap_err();
+        assert_eq!(err.desc, "Invalid netascii encoding");
+        assert_eq!(err.detail, Some("CR followed by 0x41 at offset 1".to_string()));
+
+        let mut decoder = NetasciiDecoder::new(Lf);
+        decoder.decode(b"abc\r").unwrap();
+        let err = decoder.decode(b"A").unwrap_err();
+        assert_eq!(err.detail, Some("CR followed by 0x41 at offset 4".to_string()));
+    }
+
+    #[test]
+    fn netascii_decoding_to_native_or_given_newline() {
+        let packet_bytes = b"\x00\x03\x00\x01a\r\nb\r\0c";
+        let settings = DecodeSettings { newline: Lf, ..Default::default() };
+        assert_eq!(Data(1, Vec::from_slice(b"a\nb\rc")),
+                   Packet::decode_with(NetAscii, packet_bytes, &settings).unwrap());
+        let settings = DecodeSettings { newline: CrLf, ..Default::default() };
+        assert_eq!(Data(1, Vec::from_slice(b"a\r\nb\rc")),
+                   Packet::decode_with(NetAscii, packet_bytes, &settings).unwrap());
+        assert_eq!(if cfg!(windows) { CrLf } else { Lf }, Newline::native());
+    }
+
     #[test]
     fn encoding_and_decoding_data_in_netascii_mode() {
         let packet = Data(1, Vec::from_slice(b"CR\rNL\nEND\n"));
@@ -430,6 +975,27 @@ mod bench {
         bench_decode(b, &Data(99, Vec::from_slice(b"hello\r\nworld\n")), NetAscii)
     }
 
+    #[bench]
+    fn encode_large_data_netascii(b: &mut Bencher) {
+        let mut data = Vec::new();
+        for _ in range(0u, 64) {
+            data.push_all(b"a line of netascii text\n");
+        }
+        bench_encode(b, &Data(1, data), NetAscii)
+    }
+
+    #[bench]
+    fn decode_large_data_netascii(b: &mut Bencher) {
+        let mut data = Vec::new();
+        for _ in range(0u, 64) {
+            data.push_all(b"a line of netascii text\r\n");
+        }
+        let mut packet_bytes = Vec::from_slice([0u8, 3, 0, 1]);
+        packet_bytes.push_all(data.as_slice());
+        b.iter(|| { Packet::decode(NetAscii, packet_bytes.as_slice()) });
+        b.bytes = packet_bytes.len() as u64;
+    }
+
     #[bench]
     fn encode_ack(b: &mut Bencher) {
         bench_encode(b, &Acknowledgment(21000), Octet)
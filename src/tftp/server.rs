@@ -0,0 +1,748 @@
+use std::io;
+use std::cmp::{min, max};
+use std::io::{IoResult, IoError, File, MemReader};
+use std::io::fs;
+use std::io::net::udp::UdpSocket;
+use std::io::net::ip::SocketAddr;
+use std::collections::HashMap;
+use std::default::Default;
+use std::comm::{Select, Empty, Disconnected};
+
+use protocol::{ReadRequest, WriteRequest, Data, Acknowledgment, OptionAcknowledgment};
+use protocol::{Error, Packet, Options, Mode, Octet, NetAscii, MIN_BLOCK_SIZE, MAX_BLOCK_SIZE};
+use protocol::{NetasciiReader, NetasciiDecoder};
+use protocol::{Undefined, FileNotFound, AccessViolation, FileAlreadyExists};
+use protocol::{Opcode, ACK, DATA};
+use util::{socket_reader, socket_writer, bind_socket, send_packet, receive_size, MIN_RECEIVE_SIZE};
+use client::{read_block, write_block};
+use common::{TransferOptions, NegotiatedRetry, RttEstimator};
+use common::{receive_loop, next_block_id, local_error_code, LoopData, Normal, Break, Return};
+
+static SERVE_READ_REPLIES: &'static [Opcode] = &[ACK];
+static SERVE_WRITE_REPLIES: &'static [Opcode] = &[DATA];
+
+/// Content of the file served for `ServerOptions.health_path`.
+pub static HEALTH_RESPONSE: &'static [u8] = b"OK\n";
+
+/// Filename of the directory listing served when `ServerOptions.listing` is set.
+pub static LISTING_PATH: &'static str = "__list__";
+
+/// Settings of the server itself, the ones every transfer starts from are in
+/// `transfer`.
+#[deriving(Show, Clone)]
+pub struct ServerOptions {
+    /// Settings that are not negotiated with the client
+    pub transfer: TransferOptions,
+    /// Append to an existing file on a write request instead of replacing it,
+    /// so a client can resume an interrupted upload by sending the rest
+    pub append: bool,
+    /// Replace existing files on a write request instead of replying with
+    /// `FileAlreadyExists`
+    pub allow_overwrite: bool,
+    /// Read requests for this filename are answered with `HEALTH_RESPONSE`
+    /// without touching the file system, for cheap liveness checks
+    pub health_path: Option<String>,
+    /// Answer read requests for `LISTING_PATH` with the names of the files in
+    /// the root directory, one per line in netascii
+    pub listing: bool
+}
+
+impl Default for ServerOptions {
+    fn default() -> ServerOptions {
+        ServerOptions {
+            transfer: Default::default(),
+            append: false,
+            allow_overwrite: false,
+            health_path: None,
+            listing: false
+        }
+    }
+}
+
+/// Answers read and write requests for files in `root` on `bind_addr`. Every
+/// transfer is handled in its own task from a new ephemeral port.
+pub fn serve_dir(bind_addr: SocketAddr, root: Path, opts: ServerOptions) -> IoResult<()> {
+    let socket = try!(UdpSocket::bind(bind_addr));
+    // Never signaled, but kept alive so the server does not see it disconnect
+    let (_shutdown_snd, shutdown_rcv) = channel();
+    serve(socket, root, opts, |_, _| true, shutdown_rcv)
+}
+
+/// Answers the requests received on `socket` like `serve_dir`. Every request
+/// is passed to `allow` first, the ones it returns false for are rejected with
+/// an `AccessViolation` error.
+///
+/// Stops accepting requests when `shutdown` receives a value or disconnects,
+/// and returns once the transfers in progress have finished.
+pub fn serve(mut socket: UdpSocket,
+             root: Path,
+             opts: ServerOptions,
+             allow: |SocketAddr, &Packet| -> bool,
+             shutdown: Receiver<()>) -> IoResult<()> {
+    try!(opts.transfer.validate());
+    let bind_addr = try!(socket.socket_name());
+    let mut reply_socket = socket.clone();
+    // Requests carry no data, the mode only applies to the transfer itself
+    let requests = socket_reader(socket, MIN_RECEIVE_SIZE);
+    // Every transfer task holds a sender, the receiver disconnects when all are done
+    let (running_snd, running_rcv) = channel::<()>();
+    loop {
+        let shutting_down = {
+            let select = Select::new();
+            let mut shutdown_handle = select.handle(&shutdown);
+            let mut requests_handle = select.handle(&requests);
+            unsafe {
+                shutdown_handle.add();
+                requests_handle.add();
+            }
+            select.wait() == shutdown_handle.id()
+        };
+        if shutting_down {
+            info!("Shutting down the server on {}", bind_addr.to_str());
+            break
+        }
+        let (client_addr, packet) = match requests.try_recv() {
+            Ok(request) => request,
+            Err(Empty) => continue,
+            Err(Disconnected) => break
+        };
+        if !allow(client_addr, &packet) {
+            info!("[{}] Request denied: {}", client_addr.to_str(), packet.to_str());
+            let denied = Error(AccessViolation, "Access denied".to_string());
+            match send_packet(&mut reply_socket, &client_addr, Octet, &denied) {
+                Ok(()) => {}
+                Err(err) => warn!("[{}] Sending denial failed: {}", client_addr.to_str(), err)
+            }
+            continue
+        }
+        let (root, opts, running) = (root.clone(), opts.clone(), running_snd.clone());
+        spawn(proc() {
+            let res = match packet {
+                ReadRequest(filename, mode, topts) => {
+                    serve_read(bind_addr, client_addr, &root, filename, mode, &topts, &opts)
+                }
+                WriteRequest(filename, mode, topts) => {
+                    serve_write(bind_addr, client_addr, &root, filename, mode, &topts, &opts)
+                }
+                packet => {
+                    warn!("[{}] Ignoring packet that is not a request: {}", client_addr.to_str(), packet.to_str());
+                    Ok(())
+                }
+            };
+            match res {
+                Ok(()) => info!("[{}] Transfer complete", client_addr.to_str()),
+                Err(err) => warn!("[{}] Transfer failed: {}", client_addr.to_str(), err)
+            }
+            drop(running);
+        });
+    }
+    // The request reader stops on the next datagram now that nobody receives
+    // from it, wake it so it releases the port for a restarted server
+    drop(requests);
+    let _ = send_packet(&mut reply_socket, &bind_addr, Octet, &Acknowledgment(0));
+    drop(running_snd);
+    let _ = running_rcv.recv_opt();
+    Ok(())
+}
+
+// The requested file under `root`, paths leaving it are rejected
+fn resolve(root: &Path, filename: &str) -> Option<Path> {
+    let relative = Path::new(filename.trim_left_chars('/'));
+    if relative.components().any(|c| c == b"..") {
+        None
+    } else {
+        Some(root.join(relative))
+    }
+}
+
+// The supported subset of the requested options, as they are acknowledged
+// The data to send for a read request, and its size for the `tsize` option
+fn open_source(root: &Path, filename: &str, opts: &ServerOptions) -> IoResult<(Box<Reader>, u64)> {
+    if opts.health_path.as_ref().map_or(false, |path| path.as_slice() == filename) {
+        let response = box MemReader::new(Vec::from_slice(HEALTH_RESPONSE));
+        return Ok((response as Box<Reader>, HEALTH_RESPONSE.len() as u64))
+    }
+    if is_listing(opts, filename) {
+        let listing = try!(list_dir(root));
+        let size = listing.len() as u64;
+        return Ok((box MemReader::new(listing) as Box<Reader>, size))
+    }
+    match resolve(root, filename) {
+        Some(path) => {
+            let size = try!(fs::stat(&path)).size;
+            let file = try!(File::open(&path));
+            Ok((box file as Box<Reader>, size))
+        }
+        None => Err(io::standard_error(io::PermissionDenied))
+    }
+}
+
+// Opens the target of a write request without truncating it. Whether the
+// content may be replaced is decided on the opened file, so a file created
+// after a separate existence check can not be truncated. An empty file is
+// treated like a new one.
+fn open_target(path: &Path, opts: &ServerOptions) -> IoResult<File> {
+    let mode = if opts.append { io::Append } else { io::Open };
+    let mut file = try!(File::open_mode(path, mode, io::Write));
+    if opts.append || try!(file.stat()).size == 0 {
+        return Ok(file)
+    }
+    if !opts.allow_overwrite {
+        return Err(IoError {
+            kind: io::PathAlreadyExists,
+            desc: "File already exists",
+            detail: Some(path.display().to_str())
+        })
+    }
+    try!(file.truncate(0));
+    Ok(file)
+}
+
+fn is_listing(opts: &ServerOptions, filename: &str) -> bool {
+    opts.listing && filename == LISTING_PATH
+}
+
+// Sorted names of the entries in `dir`, each on its own line
+fn list_dir(dir: &Path) -> IoResult<Vec<u8>> {
+    let mut names: Vec<String> = try!(fs::readdir(dir)).iter()
+        .filter_map(|path| path.filename_str().map(|name| name.to_string()))
+        .collect();
+    names.sort();
+    let mut listing = String::new();
+    for name in names.iter() {
+        listing.push_str(name.as_slice());
+        listing.push_char('\n');
+    }
+    Ok(listing.into_bytes())
+}
+
+fn acknowledge_options(requested: &Options, transfer_size: Option<u64>) -> Options {
+    let mut acked = HashMap::new();
+    let opts = TransferOptions::from_map(requested);
+    if requested.contains_key(&"blksize".to_string()) {
+        let block_size = max(MIN_BLOCK_SIZE, min(opts.block_size, MAX_BLOCK_SIZE));
+        acked.insert("blksize".to_string(), block_size.to_str());
+    }
+    if requested.contains_key(&"timeout".to_string()) && opts.resend_timeout > 0 {
+        acked.insert("timeout".to_string(), opts.resend_timeout.to_str());
+    }
+    match transfer_size {
+        Some(size) if requested.contains_key(&"tsize".to_string()) => {
+            acked.insert("tsize".to_string(), size.to_str());
+        }
+        _ => {}
+    }
+    acked
+}
+
+fn error_reply(err: &IoError) -> Packet {
+    match err.kind {
+        io::FileNotFound => Error(FileNotFound, "File not found".to_string()),
+        io::PermissionDenied => Error(AccessViolation, "Access violation".to_string()),
+        io::PathAlreadyExists => Error(FileAlreadyExists, "File already exists".to_string()),
+        _ => Error(Undefined, err.desc.to_string())
+    }
+}
+
+fn new_loop_data<T, D>(bind_addr: SocketAddr,
+                       client_addr: SocketAddr,
+                       opts: TransferOptions,
+                       acked: &Options,
+                       current_id: u16,
+                       handle: T,
+                       data: D) -> IoResult<LoopData<T, D>> {
+    let socket = try!(bind_socket(SocketAddr { ip: bind_addr.ip, port: 0 }));
+    // Netascii is encoded and decoded by the transfer, not per packet
+    let reader_recv = socket_reader(socket.clone(), receive_size(opts.block_size));
+    let writer_snd = socket_writer(socket);
+    let recording = if opts.record { Some(Vec::new()) } else { None };
+    Ok(LoopData {
+        remote_addr: client_addr,
+        reader_port: reader_recv,
+        unknown_port: None,
+        writer_chan: writer_snd,
+        control_port: None,
+        paused: false,
+        last_sent: None,
+        opts: opts,
+        acknowledged_options: if acked.is_empty() { None } else { Some(acked.clone()) },
+        current_id: current_id,
+        resend: false,
+        transferred: 0,
+        blocks: 0,
+        unacked: 0,
+        recording: recording,
+        rtt: RttEstimator::new(),
+        resends: 0,
+        peer_error: None,
+        timeout: None,
+        path_handle: handle,
+        data: data
+    })
+}
+
+// Sends the requested file to the client, the same way `client::put` does
+fn serve_read(bind_addr: SocketAddr,
+              client_addr: SocketAddr,
+              root: &Path,
+              filename: String,
+              mode: Mode,
+              requested: &Options,
+              server_opts: &ServerOptions) -> IoResult<()> {
+    let (file, size, err) = match open_source(root, filename.as_slice(), server_opts) {
+        Ok((file, size)) => (Some(file), Some(size), None),
+        Err(err) => (None, None, Some(err))
+    };
+    let acked = acknowledge_options(requested, size);
+    let mut opts = server_opts.transfer.negotiate(&acked);
+    // The listing is text, it is always sent with netascii line endings
+    opts.mode = if is_listing(server_opts, filename.as_slice()) { NetAscii } else { mode };
+    // Encoded as a stream, a CR LF may span two blocks
+    let file = match file {
+        Some(file) if opts.mode == NetAscii => Some(box NetasciiReader::new(file) as Box<Reader>),
+        file => file
+    };
+    let current_id = if acked.is_empty() { 1 } else { 0 };
+    let mut d = try!(new_loop_data(bind_addr, client_addr, opts, &acked, current_id, file, None::<Vec<u8>>));
+    match err {
+        Some(err) => {
+            d.send(error_reply(&err));
+            return Err(err)
+        }
+        None => {}
+    }
+    d.resend = true;
+    let mut retry = NegotiatedRetry;
+    receive_loop(&mut d, true, &mut retry, SERVE_READ_REPLIES, |_| {}, |d| {
+        if d.resend {
+            if d.current_id == 0 {
+                let acked = d.acknowledged_options.clone().unwrap();
+                d.send(OptionAcknowledgment(acked));
+            } else {
+                if d.data.is_none() {
+                    let block_size = d.opts.block_size;
+                    match read_block(&mut **d.path_handle.as_mut().unwrap(), block_size) {
+                        Ok(data) => {
+                            d.transferred += data.len() as u64;
+                            d.blocks += 1;
+                            d.log_progress();
+                            d.data = Some(data);
+                        }
+                        Err(err) => {
+                            d.send(error_reply(&err));
+                            return Return(Err(err))
+                        }
+                    }
+                }
+                let data = d.data.clone().unwrap();
+                let block_id = d.current_id;
+                d.send(Data(block_id, data));
+            }
+            d.resend = false;
+        }
+        Normal
+    }, |d, _, packet, reset| {
+        match *packet {
+            Acknowledgment(block_id) if block_id == d.current_id => {
+                if d.data.as_ref().map_or(false, |data| data.len() < d.opts.block_size) {
+                    return Break
+                }
+                d.current_id = next_block_id(&d.opts, d.current_id);
+                *reset = true;
+                d.resend = true;
+                d.data = None;
+            }
+            _ => {}
+        }
+        Normal
+    })
+}
+
+// Writes the client's file into `root`, the same way `client::get` receives it
+fn serve_write(bind_addr: SocketAddr,
+               client_addr: SocketAddr,
+               root: &Path,
+               filename: String,
+               mode: Mode,
+               requested: &Options,
+               server_opts: &ServerOptions) -> IoResult<()> {
+    let created = match resolve(root, filename.as_slice()) {
+        Some(path) => open_target(&path, server_opts),
+        None => Err(io::standard_error(io::PermissionDenied))
+    };
+    let acked = acknowledge_options(requested, TransferOptions::from_map(requested).transfer_size);
+    let mut opts = server_opts.transfer.negotiate(&acked);
+    opts.mode = mode;
+    let (file, err) = match created {
+        Ok(file) => (Some(file), None),
+        Err(err) => (None, Some(err))
+    };
+    let decoder = if opts.mode == NetAscii { Some(NetasciiDecoder::new(opts.newline.clone())) } else { None };
+    let mut d = try!(new_loop_data(bind_addr, client_addr, opts, &acked, 1, file, decoder));
+    match err {
+        Some(err) => {
+            d.send(error_reply(&err));
+            return Err(err)
+        }
+        None => {}
+    }
+    let mut retry = NegotiatedRetry;
+    receive_loop(&mut d, true, &mut retry, SERVE_WRITE_REPLIES, |d| {
+        match d.acknowledged_options.clone() {
+            Some(acked) => d.send(OptionAcknowledgment(acked)),
+            None => d.send(Acknowledgment(0))
+        }
+    }, |d| {
+        // Our last reply was lost if the client did not answer it in time
+        if d.resend {
+            match d.last_sent.clone() {
+                Some(last_sent) => d.send(last_sent),
+                None => {}
+            }
+            d.resend = false;
+        }
+        Normal
+    }, |d, _, packet, reset| {
+        match *packet {
+            Data(block_id, ref data) if block_id == d.current_id => {
+                let last = data.len() < d.opts.block_size;
+                let written = {
+                    let file = d.path_handle.as_mut().unwrap();
+                    match d.data {
+                        Some(ref mut decoder) => {
+                            decoder.decode(data.as_slice())
+                                .and_then(|decoded| write_block(file, decoded.as_slice()))
+                                .and_then(|()| if last { decoder.finish() } else { Ok(()) })
+                        }
+                        None => write_block(file, data.as_slice())
+                    }
+                };
+                match written {
+                    Ok(()) => d.transferred += data.len() as u64,
+                    Err(err) => {
+                        d.send(Error(local_error_code(&err), err.desc.to_string()));
+                        return Return(Err(err))
+                    }
+                }
+                d.current_id = next_block_id(&d.opts, d.current_id);
+                d.blocks += 1;
+                d.log_progress();
+                *reset = true;
+                d.send(Acknowledgment(block_id));
+                if last {
+                    return Break
+                }
+            }
+            _ => {}
+        }
+        Normal
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::io::{File, TempDir};
+    use std::io::net::ip::{SocketAddr, Ipv4Addr};
+    use std::default::Default;
+    use std::collections::HashMap;
+
+    use super::{ServerOptions, HEALTH_RESPONSE, LISTING_PATH, serve, resolve};
+    use client::{get, put, get_file, put_file};
+    use common::{TransferOptions, Protocol};
+    use protocol::{FileAlreadyExists, DiskFull, AccessViolation, Error, ReadRequest, WriteRequest};
+    use protocol::{Octet, NetAscii};
+    use util::{bind_socket, receive_packet, send_packet};
+
+    static EPHEMERAL: SocketAddr = SocketAddr {
+        ip: Ipv4Addr(127, 0, 0, 1),
+        port: 0
+    };
+
+    fn gen_data(len: uint) -> Vec<u8> {
+        Vec::from_fn(len, |i| (i % 251) as u8)
+    }
+
+    fn start_server(root: &Path, opts: ServerOptions) -> SocketAddr {
+        let mut socket = bind_socket(EPHEMERAL).unwrap();
+        let server_addr = socket.socket_name().unwrap();
+        let root = root.clone();
+        let (shutdown_snd, shutdown_rcv) = channel();
+        spawn(proc() {
+            // Keeps the server running until the end of the test
+            let _shutdown = shutdown_snd;
+            serve(socket, root, opts, |_, _| true, shutdown_rcv).unwrap();
+        });
+        server_addr
+    }
+
+    // Uploads `data` to the server and downloads it again
+    fn round_trip(server_addr: SocketAddr, filename: &str, data: &[u8], opts: TransferOptions) -> Vec<u8> {
+        let mut reader = io::BufReader::new(data);
+        put(server_addr, Path::new(filename), opts.clone(), &mut reader).unwrap();
+        let mut writer = io::MemWriter::new();
+        get(server_addr, Path::new(filename), opts, &mut writer).unwrap();
+        writer.unwrap()
+    }
+
+    #[test]
+    fn resolve_rejects_paths_outside_root() {
+        let root = Path::new("/srv/tftp");
+        assert!(resolve(&root, "dir/file") == Some(Path::new("/srv/tftp/dir/file")));
+        assert!(resolve(&root, "/file") == Some(Path::new("/srv/tftp/file")));
+        assert!(resolve(&root, "../etc/passwd").is_none());
+        assert!(resolve(&root, "dir/../../file").is_none());
+    }
+
+    #[test]
+    fn client_downloads_file_from_served_directory() {
+        let root = TempDir::new("tftp").unwrap();
+        let data = gen_data(3 * 512 + 100);
+        File::create(&root.path().join("file.bin")).write(data.as_slice()).unwrap();
+
+        let server_addr = start_server(root.path(), Default::default());
+
+        let mut writer = io::MemWriter::new();
+        let opts: TransferOptions = Default::default();
+        get(server_addr, Path::new("file.bin"), opts, &mut writer).unwrap();
+        assert_eq!(data.as_slice(), writer.get_ref());
+    }
+
+    #[test]
+    fn health_path_is_answered_without_a_file() {
+        let root = TempDir::new("tftp").unwrap();
+        let mut opts: ServerOptions = Default::default();
+        opts.health_path = Some("__health__".to_string());
+        let server_addr = start_server(root.path(), opts);
+
+        let mut writer = io::MemWriter::new();
+        get(server_addr, Path::new("__health__"), Default::default(), &mut writer).unwrap();
+        assert_eq!(HEALTH_RESPONSE, writer.get_ref());
+        assert!(!root.path().join("__health__").exists());
+    }
+
+    #[test]
+    fn listing_path_serves_directory_entries_as_netascii() {
+        let root = TempDir::new("tftp").unwrap();
+        File::create(&root.path().join("b.bin")).write(b"b").unwrap();
+        File::create(&root.path().join("a.txt")).write(b"a").unwrap();
+        let mut opts: ServerOptions = Default::default();
+        opts.listing = true;
+        let server_addr = start_server(root.path(), opts);
+
+        let mut writer = io::MemWriter::new();
+        get(server_addr, Path::new(LISTING_PATH), Default::default(), &mut writer).unwrap();
+        assert_eq!(b"a.txt\r\nb.bin\r\n", writer.get_ref());
+    }
+
+    #[test]
+    fn listing_path_is_an_ordinary_file_unless_enabled() {
+        let root = TempDir::new("tftp").unwrap();
+        File::create(&root.path().join(LISTING_PATH)).write(b"file").unwrap();
+        let server_addr = start_server(root.path(), Default::default());
+
+        let mut writer = io::MemWriter::new();
+        get(server_addr, Path::new(LISTING_PATH), Default::default(), &mut writer).unwrap();
+        assert_eq!(b"file", writer.get_ref());
+    }
+
+    #[test]
+    fn requests_denied_by_the_callback_get_an_access_violation() {
+        let root = TempDir::new("tftp").unwrap();
+        let data = gen_data(100);
+        File::create(&root.path().join("file.bin")).write(data.as_slice()).unwrap();
+        let mut denied_client = bind_socket(EPHEMERAL).unwrap();
+        let denied_addr = denied_client.socket_name().unwrap();
+        let mut socket = bind_socket(EPHEMERAL).unwrap();
+        let server_addr = socket.socket_name().unwrap();
+        let served_root = root.path().clone();
+        let (shutdown_snd, shutdown_rcv) = channel();
+        spawn(proc() {
+            let _shutdown = shutdown_snd;
+            serve(socket, served_root, Default::default(), |addr, _| addr != denied_addr, shutdown_rcv).unwrap();
+        });
+
+        let request = ReadRequest("file.bin".to_string(), Octet, HashMap::new());
+        send_packet(&mut denied_client, &server_addr, Octet, &request).unwrap();
+        let mut buf = [0u8, ..516];
+        let (addr, reply) = receive_packet(&mut denied_client, Octet, buf).unwrap();
+        assert_eq!(server_addr, addr);
+        assert_eq!(Error(AccessViolation, "Access denied".to_string()), reply);
+
+        let mut writer = io::MemWriter::new();
+        get(server_addr, Path::new("file.bin"), Default::default(), &mut writer).unwrap();
+        assert_eq!(data.as_slice(), writer.get_ref());
+    }
+
+    #[test]
+    fn serve_returns_when_shut_down() {
+        let root = TempDir::new("tftp").unwrap();
+        let data = gen_data(512 + 10);
+        File::create(&root.path().join("file.bin")).write(data.as_slice()).unwrap();
+        let mut socket = bind_socket(EPHEMERAL).unwrap();
+        let server_addr = socket.socket_name().unwrap();
+        let served_root = root.path().clone();
+        let (shutdown_snd, shutdown_rcv) = channel();
+        let (result_snd, result_rcv) = channel();
+        spawn(proc() {
+            result_snd.send(serve(socket, served_root, Default::default(), |_, _| true, shutdown_rcv));
+        });
+
+        // Requests are still served until the shutdown is signaled
+        let mut writer = io::MemWriter::new();
+        get(server_addr, Path::new("file.bin"), Default::default(), &mut writer).unwrap();
+        assert_eq!(data.as_slice(), writer.get_ref());
+
+        shutdown_snd.send(());
+        assert!(result_rcv.recv().is_ok());
+    }
+
+    #[test]
+    fn client_uploads_file_into_served_directory() {
+        let root = TempDir::new("tftp").unwrap();
+        let data = gen_data(2 * 1024 + 10);
+
+        let server_addr = start_server(root.path(), Default::default());
+
+        let mut opts: TransferOptions = Default::default();
+        opts.block_size = 1024;
+        let mut reader = io::BufReader::new(data.as_slice());
+        put(server_addr, Path::new("upload.bin"), opts, &mut reader).unwrap();
+        let written = File::open(&root.path().join("upload.bin")).read_to_end().unwrap();
+        assert_eq!(data, written);
+    }
+
+    #[test]
+    fn client_file_helpers_upload_and_download_local_files() {
+        let root = TempDir::new("tftp").unwrap();
+        let local = TempDir::new("tftp-local").unwrap();
+        let data = gen_data(2 * 512 + 10);
+        let source = local.path().join("source.bin");
+        File::create(&source).write(data.as_slice()).unwrap();
+        let server_addr = start_server(root.path(), Default::default());
+
+        put_file(server_addr, &source, Path::new("file.bin"), Default::default()).unwrap();
+        // A longer existing file is truncated
+        let target = local.path().join("target.bin");
+        File::create(&target).write(gen_data(4096).as_slice()).unwrap();
+        get_file(server_addr, Path::new("file.bin"), &target, Default::default()).unwrap();
+        assert_eq!(data, File::open(&target).read_to_end().unwrap());
+    }
+
+    #[test]
+    fn put_file_sends_local_file_size_with_auto_transfer_size() {
+        let local = TempDir::new("tftp-local").unwrap();
+        let source = local.path().join("source.bin");
+        File::create(&source).write(gen_data(2048).as_slice()).unwrap();
+        let mut peer = bind_socket(EPHEMERAL).unwrap();
+        let peer_addr = peer.socket_name().unwrap();
+        let (request_snd, request_rcv) = channel();
+        spawn(proc() {
+            let mut peer = peer;
+            let mut buf = [0u8, ..516];
+            let (addr, request) = receive_packet(&mut peer, Octet, buf).unwrap();
+            send_packet(&mut peer, &addr, Octet, &Error(DiskFull, "Disk full".to_string())).unwrap();
+            request_snd.send(request);
+        });
+
+        let mut opts: TransferOptions = Default::default();
+        opts.auto_transfer_size = true;
+        let err = put_file(peer_addr, &source, Path::new("file.bin"), opts).unwrap_err();
+        assert_eq!(Protocol(DiskFull, "Disk full".to_string()), err);
+        let mut expected = HashMap::new();
+        expected.insert("tsize".to_string(), "2048".to_string());
+        assert_eq!(WriteRequest("file.bin".to_string(), Octet, expected), request_rcv.recv());
+    }
+
+    // The loopback round trips bind real sockets, run them with `--ignored`
+    #[test]
+    #[ignore]
+    fn loopback_round_trip_of_small_file() {
+        let root = TempDir::new("tftp").unwrap();
+        let server_addr = start_server(root.path(), Default::default());
+        let data = gen_data(100);
+        assert_eq!(data, round_trip(server_addr, "small.bin", data.as_slice(), Default::default()));
+        assert_eq!(data, File::open(&root.path().join("small.bin")).read_to_end().unwrap());
+    }
+
+    #[test]
+    #[ignore]
+    fn loopback_round_trip_of_exact_block_multiple() {
+        let root = TempDir::new("tftp").unwrap();
+        let server_addr = start_server(root.path(), Default::default());
+        // Ends with an empty block
+        let data = gen_data(4 * 512);
+        assert_eq!(data, round_trip(server_addr, "default.bin", data.as_slice(), Default::default()));
+        assert_eq!(data, File::open(&root.path().join("default.bin")).read_to_end().unwrap());
+
+        let mut opts: TransferOptions = Default::default();
+        opts.block_size = 1024;
+        let data = gen_data(3 * 1024);
+        assert_eq!(data, round_trip(server_addr, "negotiated.bin", data.as_slice(), opts));
+        assert_eq!(data, File::open(&root.path().join("negotiated.bin")).read_to_end().unwrap());
+    }
+
+    #[test]
+    #[ignore]
+    fn loopback_round_trip_of_netascii_file() {
+        let root = TempDir::new("tftp").unwrap();
+        let server_addr = start_server(root.path(), Default::default());
+        let mut data = Vec::new();
+        for i in range(0u, 200) {
+            data.push_all(format!("line {}\n", i).as_bytes());
+        }
+        data.push_all(b"bare\rcarriage return\n");
+        let mut opts: TransferOptions = Default::default();
+        opts.mode = NetAscii;
+        assert_eq!(data, round_trip(server_addr, "text.txt", data.as_slice(), opts));
+    }
+
+    #[test]
+    fn netascii_line_break_split_between_blocks_round_trips() {
+        let root = TempDir::new("tftp").unwrap();
+        // The CR of the encoded line break ends the first block, the LF starts the second
+        let mut data = Vec::from_elem(511, b'a');
+        data.push(b'\n');
+        data.push_all(Vec::from_elem(100, b'b').as_slice());
+        let server_addr = start_server(root.path(), Default::default());
+
+        let mut opts: TransferOptions = Default::default();
+        opts.mode = NetAscii;
+        assert_eq!(data, round_trip(server_addr, "text.txt", data.as_slice(), opts));
+    }
+
+    #[test]
+    fn upload_to_existing_file_is_rejected_unless_overwrite_is_allowed() {
+        let root = TempDir::new("tftp").unwrap();
+        let existing = root.path().join("existing.bin");
+        File::create(&existing).write(b"old content").unwrap();
+        let server_addr = start_server(root.path(), Default::default());
+
+        let mut reader = io::BufReader::new(b"new");
+        let err = put(server_addr, Path::new("existing.bin"), Default::default(), &mut reader).unwrap_err();
+        assert_eq!(Protocol(FileAlreadyExists, "File already exists".to_string()), err);
+        assert_eq!(Vec::from_slice(b"old content"), File::open(&existing).read_to_end().unwrap());
+
+        let mut opts: ServerOptions = Default::default();
+        opts.allow_overwrite = true;
+        let server_addr = start_server(root.path(), opts);
+        let mut reader = io::BufReader::new(b"new");
+        put(server_addr, Path::new("existing.bin"), Default::default(), &mut reader).unwrap();
+        assert_eq!(Vec::from_slice(b"new"), File::open(&existing).read_to_end().unwrap());
+    }
+
+    #[test]
+    fn upload_to_existing_file_is_appended_in_append_mode() {
+        let root = TempDir::new("tftp").unwrap();
+        let data = gen_data(2 * 512 + 10);
+        let existing = root.path().join("existing.bin");
+        File::create(&existing).write(data.slice_to(700)).unwrap();
+        let mut opts: ServerOptions = Default::default();
+        opts.append = true;
+        let server_addr = start_server(root.path(), opts);
+
+        let mut reader = io::BufReader::new(data.slice_from(700));
+        put(server_addr, Path::new("existing.bin"), Default::default(), &mut reader).unwrap();
+        assert_eq!(data, File::open(&existing).read_to_end().unwrap());
+    }
+}
@@ -2,24 +2,99 @@ use std::io::{IoResult, IoError, InvalidInput};
 use std::io::net::udp::UdpSocket;
 use std::io::net::ip::{SocketAddr, IpAddr};
 
-use std::rand::random;
+use std::rand::{Rng, task_rng};
 
-use protocol::{Mode, Packet};
+use std::cmp::{min, max};
+
+use protocol::{Mode, Octet, Packet, Opcode, MAX_BLOCK_SIZE};
 
 pub fn random_ephemeral_port() -> u16 {
-    let min = 49152;
-    let max = 65535;
-    random::<u16>() % (max - min) + min
+    random_ephemeral_port_from(&mut task_rng())
+}
+
+pub fn random_ephemeral_port_from<R: Rng>(rng: &mut R) -> u16 {
+    // IANA dynamic port range, both ends inclusive. Sampling a range rejects
+    // the values that would make some ports more likely than others.
+    let min = 49152u32;
+    let max = 65535u32;
+    rng.gen_range(min, max + 1) as u16
+}
+
+/// Number of ports tried before giving up on binding a socket.
+pub static BIND_ATTEMPTS: uint = 5;
+
+pub fn packet_size(block_size: uint) -> uint {
+    // Opcode and block id precede the data
+    (min(block_size as u64, MAX_BLOCK_SIZE as u64) + 4) as uint
+}
+
+/// Smallest receive buffer, so that control packets like a long Error or OACK
+/// are not truncated when the block size is tiny.
+pub static MIN_RECEIVE_SIZE: uint = 1500;
+
+pub fn receive_size(block_size: uint) -> uint {
+    max(packet_size(block_size), MIN_RECEIVE_SIZE)
+}
+
+/// Bytes of block checksum at the end of a data block when `crc` is negotiated.
+pub static CRC_SIZE: uint = 2;
+
+// CRC-16/CCITT
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xffffu16;
+    for &byte in data.iter() {
+        crc = crc ^ (byte as u16 << 8);
+        for _ in range(0u, 8) {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+pub fn append_crc(data: &mut Vec<u8>) {
+    let crc = crc16(data.as_slice());
+    data.push((crc >> 8) as u8);
+    data.push(crc as u8);
+}
+
+/// The payload of a data block if its trailing checksum matches.
+pub fn strip_crc<'a>(block: &'a [u8]) -> Option<&'a [u8]> {
+    if block.len() < CRC_SIZE {
+        return None
+    }
+    let (data, crc) = (block.slice_to(block.len() - CRC_SIZE), block.slice_from(block.len() - CRC_SIZE));
+    if crc16(data) == (crc[0] as u16 << 8 | crc[1] as u16) {
+        Some(data)
+    } else {
+        None
+    }
 }
 
 pub fn receive_packet(socket: &mut UdpSocket, mode: Mode, buf: &mut [u8]) -> IoResult<(SocketAddr, Packet)> {
+    let (len, addr) = try!(receive_datagram(socket, buf));
+    let packet = try!(decode_packet(addr, mode, buf.slice_to(len)));
+    Ok((addr, packet))
+}
+
+fn receive_datagram(socket: &mut UdpSocket, buf: &mut [u8]) -> IoResult<(uint, SocketAddr)> {
     let (len, addr) = try!(socket.recvfrom(buf));
     debug!("[{}] Got {} bytes: {}", addr.to_str(), len, buf.slice_to(len).to_str());
-    let packet_bytes = buf.slice_to(len);
+    if len == buf.len() {
+        warn!("[{}] Datagram filled the whole {} byte buffer, possibly truncated", addr.to_str(), len);
+        return Err(IoError {
+            kind: InvalidInput,
+            desc: "Received packet was truncated",
+            detail: None
+        })
+    }
+    Ok((len, addr))
+}
+
+fn decode_packet(addr: SocketAddr, mode: Mode, packet_bytes: &[u8]) -> IoResult<Packet> {
     match Packet::decode(mode, packet_bytes) {
         Ok(packet) => {
             info!("[{}] Got packet {}", addr.to_str(), packet.to_str());
-            Ok((addr, packet))
+            Ok(packet)
         },
         Err(err) => {
             warn!("[{}] Error decoding packet: {}", addr.to_str(), err);
@@ -29,6 +104,18 @@ pub fn receive_packet(socket: &mut UdpSocket, mode: Mode, buf: &mut [u8]) -> IoR
     }
 }
 
+/// The opcode of a datagram, if it is not one of the known ones.
+fn unknown_opcode(packet_bytes: &[u8]) -> Option<u16> {
+    if packet_bytes.len() < 2 {
+        return None
+    }
+    let opcode = (packet_bytes[0] as u16 << 8) | packet_bytes[1] as u16;
+    match Opcode::from_u16(opcode) {
+        Some(_) => None,
+        None => Some(opcode)
+    }
+}
+
 pub fn send_packet(socket: &mut UdpSocket, addr: &SocketAddr, mode: Mode, p: &Packet) -> IoResult<()> {
     match Packet::encode(mode, p) {
         Ok(packet_bytes) => {
@@ -48,37 +135,98 @@ pub fn send_packet(socket: &mut UdpSocket, addr: &SocketAddr, mode: Mode, p: &Pa
 
 }
 
-pub fn bind_socket(addr: IpAddr) -> IoResult<UdpSocket> {
-    let rand_port = random_ephemeral_port();
-    UdpSocket::bind(SocketAddr {
-        ip: addr,
-        port: rand_port
-    })
+/// Binds to `addr`, or to a random ephemeral port on its address when the
+/// port is 0.
+pub fn bind_socket(addr: SocketAddr) -> IoResult<UdpSocket> {
+    if addr.port == 0 {
+        bind_socket_with_rng(addr.ip, &mut task_rng())
+    } else {
+        UdpSocket::bind(addr)
+    }
 }
 
-pub fn socket_reader(us: UdpSocket, mode: Mode, packet_size: uint) -> Receiver<(SocketAddr, Packet)> {
+pub fn bind_socket_with_rng<R: Rng>(addr: IpAddr, rng: &mut R) -> IoResult<UdpSocket> {
+    bind_socket_with(addr, || random_ephemeral_port_from(&mut *rng))
+}
+
+/// Binds to the ports returned by `port_selector` until one is free, at most
+/// `BIND_ATTEMPTS` times.
+pub fn bind_socket_with(addr: IpAddr, port_selector: || -> u16) -> IoResult<UdpSocket> {
+    let mut attempt = 1;
+    loop {
+        let port = port_selector();
+        debug!("Binding socket to {}:{}", addr, port);
+        match UdpSocket::bind(SocketAddr { ip: addr, port: port }) {
+            Err(err) => {
+                if attempt >= BIND_ATTEMPTS {
+                    return Err(err)
+                }
+                info!("Binding to port {} failed: {}", port, err);
+                attempt += 1;
+            }
+            socket => return socket
+        }
+    }
+}
+
+/// Receives packets on `us`. Data payloads are passed on unchanged whatever the
+/// transfer mode is, netascii is translated by the transfer itself.
+pub fn socket_reader(us: UdpSocket, packet_size: uint) -> Receiver<(SocketAddr, Packet)> {
+    spawn_reader(us, packet_size, None)
+}
+
+/// Like `socket_reader`, but datagrams with an unknown opcode are reported on
+/// the second receiver instead of being dropped.
+pub fn strict_socket_reader(us: UdpSocket,
+                            packet_size: uint) -> (Receiver<(SocketAddr, Packet)>, Receiver<(SocketAddr, u16)>) {
+    let (unknown_snd, unknown_rcv) = channel();
+    (spawn_reader(us, packet_size, Some(unknown_snd)), unknown_rcv)
+}
+
+fn spawn_reader(us: UdpSocket,
+                packet_size: uint,
+                unknown: Option<Sender<(SocketAddr, u16)>>) -> Receiver<(SocketAddr, Packet)> {
     let (snd, rcv) = channel();
     spawn(proc() {
         let mut socket = us;
-        let mut buf = Vec::from_elem(packet_size, 0u8);
+        // One spare byte so that a datagram of exactly packet_size is not mistaken for a truncated one
+        let mut buf = Vec::from_elem(packet_size + 1, 0u8);
         loop {
-            match receive_packet(&mut socket, mode, buf.as_mut_slice()) {
-                Ok(res) => snd.send(res),
-                Err(err) => warn!("Error occured while reading: {}", err)
+            let (len, addr) = match receive_datagram(&mut socket, buf.as_mut_slice()) {
+                Ok(res) => res,
+                Err(err) => {
+                    warn!("Error occured while reading: {}", err);
+                    continue
+                }
+            };
+            let packet_bytes = buf.slice_to(len);
+            match decode_packet(addr, Octet, packet_bytes) {
+                Ok(packet) => {
+                    if snd.send_opt((addr, packet)).is_err() {
+                        debug!("Nobody is receiving packets anymore, closing reader");
+                        return
+                    }
+                }
+                Err(_) => match (unknown_opcode(packet_bytes), &unknown) {
+                    (Some(opcode), &Some(ref unknown_snd)) => unknown_snd.send((addr, opcode)),
+                    _ => {}
+                }
             }
         }
     });
     rcv
 }
 
-pub fn socket_writer(us: UdpSocket, mode: Mode) -> Sender<(SocketAddr, Packet)> {
+/// Sends the packets given to the returned sender from `us`, Data payloads are
+/// sent unchanged like `socket_reader` receives them.
+pub fn socket_writer(us: UdpSocket) -> Sender<(SocketAddr, Packet)> {
     let (snd, rcv) = channel::<(SocketAddr, Packet)>();
     spawn(proc() {
         let mut socket = us;
         loop {
             match rcv.recv_opt() {
                 Ok((addr, packet)) => {
-                    let res = send_packet(&mut socket, &addr, mode, &packet);
+                    let res = send_packet(&mut socket, &addr, Octet, &packet);
                     if res.is_err() {
                         info!("Error occured while writing: {}", res.unwrap_err())
                     }
@@ -93,3 +241,172 @@ pub fn socket_writer(us: UdpSocket, mode: Mode) -> Sender<(SocketAddr, Packet)>
     snd
 }
 
+
+#[cfg(test)]
+mod test {
+    use std::cmp::{min, max};
+    use std::io::net::ip::{SocketAddr, Ipv4Addr};
+
+    use std::uint;
+
+    use std::rand::{Rng, SeedableRng, XorShiftRng};
+
+    use super::{bind_socket, bind_socket_with, bind_socket_with_rng, random_ephemeral_port};
+    use super::random_ephemeral_port_from;
+    use super::{receive_packet, packet_size, receive_size, send_packet};
+    use super::{socket_reader, strict_socket_reader, socket_writer};
+    use super::{crc16, append_crc, strip_crc};
+    use protocol::{Octet, Acknowledgment, Data, Error, Undefined, MAX_BLOCK_SIZE};
+
+    static EPHEMERAL: SocketAddr = SocketAddr {
+        ip: Ipv4Addr(127, 0, 0, 1),
+        port: 0
+    };
+
+    #[test]
+    fn crc16_of_check_string() {
+        assert_eq!(0x29b1, crc16(b"123456789"));
+    }
+
+    #[test]
+    fn strip_crc_only_accepts_matching_checksum() {
+        let mut block = Vec::from_slice(b"some data");
+        append_crc(&mut block);
+        assert_eq!(Some(b"some data"), strip_crc(block.as_slice()));
+        *block.get_mut(0) ^= 1;
+        assert_eq!(None, strip_crc(block.as_slice()));
+        assert_eq!(None, strip_crc([0u8]));
+    }
+
+    #[test]
+    fn bind_socket_uses_port_from_selector() {
+        let port = random_ephemeral_port();
+        let mut socket = bind_socket_with(Ipv4Addr(127, 0, 0, 1), || port).unwrap();
+        assert_eq!(port, socket.socket_name().unwrap().port);
+    }
+
+    struct ScriptedRng {
+        values: Vec<u32>
+    }
+
+    impl Rng for ScriptedRng {
+        fn next_u32(&mut self) -> u32 {
+            self.values.remove(0).unwrap()
+        }
+    }
+
+    #[test]
+    fn random_ephemeral_port_is_uniform_over_inclusive_range() {
+        let mut rng: XorShiftRng = SeedableRng::from_seed([1, 2, 3, 4]);
+        // 16 buckets of 1024 ports each
+        let mut buckets = [0u, ..16];
+        let (mut lowest, mut highest) = (65535u16, 0u16);
+        let draws = 16384 * 20;
+        for _ in range(0u, draws) {
+            let port = random_ephemeral_port_from(&mut rng);
+            assert!(port >= 49152);
+            buckets[((port - 49152) / 1024) as uint] += 1;
+            lowest = min(lowest, port);
+            highest = max(highest, port);
+        }
+        assert_eq!(49152, lowest);
+        assert_eq!(65535, highest);
+        let expected = draws / 16;
+        for &count in buckets.iter() {
+            assert!(count > expected * 95 / 100 && count < expected * 105 / 100, "bucket count {}", count);
+        }
+    }
+
+    #[test]
+    fn bind_socket_retries_with_next_port_when_port_is_taken() {
+        let mut taken = bind_socket(EPHEMERAL).unwrap();
+        let taken_port = taken.socket_name().unwrap().port;
+        let mut free_port = random_ephemeral_port();
+        while free_port == taken_port {
+            free_port = random_ephemeral_port();
+        }
+        let mut rng = ScriptedRng {
+            values: vec![(taken_port - 49152) as u32, (free_port - 49152) as u32]
+        };
+        let mut socket = bind_socket_with_rng(Ipv4Addr(127, 0, 0, 1), &mut rng).unwrap();
+        assert_eq!(free_port, socket.socket_name().unwrap().port);
+        assert!(rng.values.is_empty());
+    }
+
+    #[test]
+    fn packet_size_adds_header_to_block_size() {
+        assert_eq!(516, packet_size(512));
+        assert_eq!(MAX_BLOCK_SIZE + 4, packet_size(MAX_BLOCK_SIZE));
+    }
+
+    #[test]
+    fn packet_size_does_not_overflow_for_huge_block_size() {
+        assert_eq!(MAX_BLOCK_SIZE + 4, packet_size(uint::MAX));
+        assert_eq!(MAX_BLOCK_SIZE + 4, packet_size(0xffff));
+    }
+
+    #[test]
+    fn receive_packet_fails_when_datagram_fills_the_buffer() {
+        let mut receiver = bind_socket(EPHEMERAL).unwrap();
+        let mut sender = bind_socket(EPHEMERAL).unwrap();
+        let addr = receiver.socket_name().unwrap();
+        sender.sendto([0u8, 4, 0, 1], addr).unwrap();
+        let mut buf = [0u8, ..4];
+        assert!(receive_packet(&mut receiver, Octet, buf).is_err());
+    }
+
+    #[test]
+    fn receive_packet_decodes_datagram_smaller_than_the_buffer() {
+        let mut receiver = bind_socket(EPHEMERAL).unwrap();
+        let mut sender = bind_socket(EPHEMERAL).unwrap();
+        let addr = receiver.socket_name().unwrap();
+        sender.sendto([0u8, 4, 0, 1], addr).unwrap();
+        let mut buf = [0u8, ..5];
+        let (_, packet) = receive_packet(&mut receiver, Octet, buf).unwrap();
+        assert_eq!(Acknowledgment(1), packet);
+    }
+
+    #[test]
+    fn strict_socket_reader_reports_unknown_opcode() {
+        let receiver = bind_socket(EPHEMERAL).unwrap();
+        let mut sender = bind_socket(EPHEMERAL).unwrap();
+        let addr = receiver.clone().socket_name().unwrap();
+        let (packets, unknown) = strict_socket_reader(receiver, packet_size(512));
+        sender.sendto([0u8, 0x99, 0, 1], addr).unwrap();
+        sender.sendto([0u8, 4, 0, 1], addr).unwrap();
+        let sender_addr = sender.socket_name().unwrap();
+        assert_eq!((sender_addr, 0x99), unknown.recv());
+        assert_eq!((sender_addr, Acknowledgment(1)), packets.recv());
+    }
+
+    #[test]
+    fn socket_tasks_pass_data_payload_unchanged() {
+        let receiver = bind_socket(EPHEMERAL).unwrap();
+        let sender = bind_socket(EPHEMERAL).unwrap();
+        let addr = receiver.clone().socket_name().unwrap();
+        let packets = socket_reader(receiver, packet_size(512));
+        let data = Data(1, Vec::from_slice(b"a\r\nb\r\x00"));
+        socket_writer(sender).send((addr, data.clone()));
+        let (_, packet) = packets.recv();
+        assert_eq!(data, packet);
+    }
+
+    #[test]
+    fn receive_size_has_a_floor_for_control_packets() {
+        assert_eq!(1500, receive_size(8));
+        assert_eq!(1500, receive_size(512));
+        assert_eq!(MAX_BLOCK_SIZE + 4, receive_size(MAX_BLOCK_SIZE));
+    }
+
+    #[test]
+    fn socket_reader_receives_long_error_with_tiny_block_size() {
+        let receiver = bind_socket(EPHEMERAL).unwrap();
+        let mut sender = bind_socket(EPHEMERAL).unwrap();
+        let addr = receiver.clone().socket_name().unwrap();
+        let packets = socket_reader(receiver, receive_size(8));
+        let error = Error(Undefined, String::from_char(200, 'x'));
+        send_packet(&mut sender, &addr, Octet, &error).unwrap();
+        let (_, packet) = packets.recv();
+        assert_eq!(error, packet);
+    }
+}